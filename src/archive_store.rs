@@ -0,0 +1,209 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Datelike, NaiveDateTime, Utc};
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::config::BackupRetention;
+
+const ID_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+const HASH_PREFIX_LEN: usize = 12;
+
+/// Directory of Teleporter archives, identified by their creation timestamp and,
+/// for content-addressed stores, a prefix of the archive's content hash
+#[derive(Debug, Clone)]
+pub struct ArchiveStore {
+    dir: PathBuf,
+}
+
+#[derive(Debug, Clone)]
+pub struct StoredArchive {
+    pub id: String,
+    pub path: PathBuf,
+    pub created: DateTime<Utc>,
+    pub content_hash: Option<String>,
+    pub size: u64,
+}
+
+impl ArchiveStore {
+    pub fn new<P: AsRef<Path>>(dir: P) -> Self {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Store for the pre-sync snapshots of a secondary
+    pub fn snapshots<P: AsRef<Path>>(cache_location: P, host: &str) -> Self {
+        Self::new(cache_location.as_ref().join("snapshots").join(host))
+    }
+
+    /// Store for the archive history of the main instance
+    pub fn backups<P: AsRef<Path>>(cache_location: P) -> Self {
+        Self::new(cache_location.as_ref().join("backups"))
+    }
+
+    pub fn save(&self, bytes: &[u8]) -> Result<StoredArchive> {
+        self.write(bytes, None)
+    }
+
+    /// Stores the archive under its content hash, unless the latest stored archive
+    /// already has the same content. Returns the stored archive and whether it is new.
+    pub fn save_content(&self, bytes: &[u8], content_hash: &str) -> Result<(StoredArchive, bool)> {
+        let hash_prefix = &content_hash[..HASH_PREFIX_LEN.min(content_hash.len())];
+
+        if let Some(latest) = self.latest()? {
+            if latest.content_hash.as_deref() == Some(hash_prefix) {
+                return Ok((latest, false));
+            }
+        }
+
+        Ok((self.write(bytes, Some(hash_prefix))?, true))
+    }
+
+    fn write(&self, bytes: &[u8], content_hash: Option<&str>) -> Result<StoredArchive> {
+        fs::create_dir_all(&self.dir)
+            .with_context(|| format!("Failed to create archive directory {:?}", self.dir))?;
+
+        let created = Utc::now();
+        let timestamp = created.format(ID_FORMAT).to_string();
+        let id = match content_hash {
+            Some(hash) => format!("{}-{}", timestamp, hash),
+            None => timestamp,
+        };
+        let path = self.dir.join(format!("{}.zip", id));
+
+        fs::write(&path, bytes).with_context(|| format!("Failed to write archive {:?}", path))?;
+
+        Ok(StoredArchive {
+            id,
+            path,
+            created,
+            content_hash: content_hash.map(str::to_string),
+            size: bytes.len() as u64,
+        })
+    }
+
+    /// Lists stored archives, oldest first
+    pub fn list(&self) -> Result<Vec<StoredArchive>> {
+        if !self.dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut archives = Vec::new();
+        for entry in fs::read_dir(&self.dir)
+            .with_context(|| format!("Failed to read archive directory {:?}", self.dir))?
+        {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("zip") {
+                continue;
+            }
+
+            let Some(id) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                continue;
+            };
+            let (timestamp, content_hash) = match id.split_once('-') {
+                Some((timestamp, hash)) => (timestamp, Some(hash.to_string())),
+                None => (id, None),
+            };
+            let Ok(created) = NaiveDateTime::parse_from_str(timestamp, ID_FORMAT) else {
+                continue;
+            };
+
+            archives.push(StoredArchive {
+                id: id.to_string(),
+                size: fs::metadata(&path)?.len(),
+                created: created.and_utc(),
+                content_hash,
+                path,
+            });
+        }
+
+        archives.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(archives)
+    }
+
+    /// Finds an archive by its full ID, its timestamp or its content hash prefix
+    pub fn get(&self, id: &str) -> Result<Option<StoredArchive>> {
+        Ok(self.list()?.into_iter().rev().find(|archive| {
+            archive.id == id
+                || archive.id.starts_with(&format!("{}-", id))
+                || archive.content_hash.as_deref() == Some(id)
+        }))
+    }
+
+    pub fn latest(&self) -> Result<Option<StoredArchive>> {
+        Ok(self.list()?.pop())
+    }
+
+    /// Deletes all but the newest `count` archives and returns the deleted ones
+    pub fn keep_last(&self, count: usize) -> Result<Vec<StoredArchive>> {
+        let archives = self.list()?;
+        let excess = archives.len().saturating_sub(count);
+
+        let mut removed = Vec::new();
+        for archive in archives.into_iter().take(excess) {
+            fs::remove_file(&archive.path)
+                .with_context(|| format!("Failed to delete archive {:?}", archive.path))?;
+            removed.push(archive);
+        }
+
+        Ok(removed)
+    }
+
+    /// Deletes all archives that are not kept by the retention policy and returns them.
+    /// With `dry_run`, nothing is deleted.
+    pub fn prune(&self, retention: &BackupRetention, dry_run: bool) -> Result<Vec<StoredArchive>> {
+        let archives = self.list()?;
+        let kept = kept_by_retention(&archives, retention);
+
+        let mut removed = Vec::new();
+        for archive in archives {
+            if kept.contains(&archive.id) {
+                continue;
+            }
+
+            if !dry_run {
+                fs::remove_file(&archive.path)
+                    .with_context(|| format!("Failed to delete archive {:?}", archive.path))?;
+            }
+            removed.push(archive);
+        }
+
+        Ok(removed)
+    }
+}
+
+/// IDs of the archives (given oldest first) a retention policy keeps: the newest `keep_last`
+/// archives plus the newest archive of each of the last `keep_daily` days and
+/// `keep_weekly` ISO weeks that have archives. The newest archive is always kept.
+fn kept_by_retention(archives: &[StoredArchive], retention: &BackupRetention) -> HashSet<String> {
+    let mut kept = HashSet::new();
+    if let Some(newest) = archives.last() {
+        kept.insert(newest.id.clone());
+    }
+
+    let mut days = Vec::new();
+    let mut weeks = Vec::new();
+
+    for (index, archive) in archives.iter().rev().enumerate() {
+        if index < retention.keep_last {
+            kept.insert(archive.id.clone());
+        }
+
+        let day = archive.created.date_naive();
+        if !days.contains(&day) && days.len() < retention.keep_daily {
+            days.push(day);
+            kept.insert(archive.id.clone());
+        }
+
+        let week = archive.created.iso_week();
+        if !weeks.contains(&week) && weeks.len() < retention.keep_weekly {
+            weeks.push(week);
+            kept.insert(archive.id.clone());
+        }
+    }
+
+    kept
+}
@@ -1,5 +1,9 @@
 mod app_password;
+mod backup;
+mod diff;
 mod instances;
+mod restore;
+mod status;
 mod sync;
 
 use std::path::Path;
@@ -8,10 +12,14 @@ use crate::config::Config;
 
 use anyhow::Result;
 use app_password::acquire_app_password;
+use backup::{run_backup_cmd, Backup};
 use clap::{Parser, Subcommand};
+use diff::{run_diff, OutputFormat};
 use instances::{run_instances_cmd, Instances};
-use sync::run_sync;
-use tracing::{info, warn};
+use restore::run_restore;
+use status::run_status;
+use sync::{run_dry_run, run_sync};
+use tracing::{error, info, warn};
 
 #[derive(Parser)]
 #[command(name = "pihole-sync")]
@@ -32,13 +40,48 @@ enum Commands {
         /// Run once and exit
         #[arg(short, long, action)]
         once: bool,
+
+        /// Upload to all secondaries even if the main instance's archive has not changed
+        #[arg(short, long, action)]
+        force: bool,
+
+        /// Show what would change on each secondary without uploading anything
+        #[arg(long, action)]
+        dry_run: bool,
     },
 
     /// Acquire an app password for a Pi-hole instance
     AppPassword,
 
+    /// Restore a pre-sync snapshot to a secondary instance
+    Restore {
+        /// Hostname of the secondary instance
+        host: String,
+
+        /// Snapshot to restore (defaults to the latest one)
+        snapshot_id: Option<String>,
+    },
+
+    /// Show the sync health of all instances
+    Status,
+
+    /// Compare the main instance with secondaries without changing anything.
+    /// Exits with 0 if all compared secondaries are in sync, 1 if any has drifted and 2 if the
+    /// comparison failed.
+    Diff {
+        /// Hostname of the secondary to compare (defaults to all enabled secondaries)
+        host: Option<String>,
+
+        /// Output format
+        #[arg(short, long, value_enum, default_value_t)]
+        output: OutputFormat,
+    },
+
     #[command(subcommand)]
     Instances(Instances),
+
+    #[command(subcommand)]
+    Backup(Backup),
 }
 
 impl Cli {
@@ -72,20 +115,52 @@ impl Cli {
         info!("Using config: {}", config_path_str);
 
         if let Some(command) = cli.command {
-            let mut config = Config::load(config_path_str)?;
+            let mut config = match Config::load(config_path_str) {
+                Ok(config) => config,
+                Err(e) if matches!(command, Commands::Diff { .. }) => exit_diff_error(e),
+                Err(e) => return Err(e),
+            };
 
             match command {
-                Commands::Sync { once } => {
-                    run_sync(config_path_str, once).await?;
+                Commands::Sync {
+                    once,
+                    force,
+                    dry_run,
+                } => {
+                    if dry_run {
+                        run_dry_run(config_path_str).await?;
+                    } else {
+                        run_sync(config_path_str, once, force).await?;
+                    }
                 }
 
                 Commands::AppPassword => {
                     acquire_app_password(config_path_str).await?;
                 }
 
+                Commands::Restore { host, snapshot_id } => {
+                    run_restore(config_path_str, &host, snapshot_id).await?;
+                }
+
+                Commands::Status => {
+                    run_status(&config)?;
+                }
+
+                Commands::Diff { host, output } => {
+                    match run_diff(&config, host.as_deref(), output).await {
+                        Ok(true) => std::process::exit(1),
+                        Ok(false) => {}
+                        Err(e) => exit_diff_error(e),
+                    }
+                }
+
                 Commands::Instances(instances_cmd) => {
                     run_instances_cmd(instances_cmd, &mut config, config_path_str)?;
                 }
+
+                Commands::Backup(backup_cmd) => {
+                    run_backup_cmd(backup_cmd, &config)?;
+                }
             }
             return Ok(()); // Exit after CLI command execution
         } else {
@@ -95,3 +170,9 @@ impl Cli {
         Ok(())
     }
 }
+
+/// Exits `diff` with 2 like diff(1), so a failed comparison is not mistaken for drift
+fn exit_diff_error(e: anyhow::Error) -> ! {
+    error!("Error: {:?}", e);
+    std::process::exit(2);
+}
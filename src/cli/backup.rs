@@ -0,0 +1,90 @@
+use anyhow::{anyhow, Result};
+use clap::Subcommand;
+
+use crate::{
+    archive_store::{ArchiveStore, StoredArchive},
+    config::{Config, SyncImportOptions},
+    diff::Snapshot,
+    teleporter::TeleporterArchive,
+};
+
+#[derive(Subcommand)]
+/// Manage the archive history of the main instance
+pub enum Backup {
+    /// List all stored archives
+    List,
+
+    /// Show details of a stored archive
+    Show {
+        /// Archive ID, timestamp or content hash ("latest" for the newest archive)
+        id: String,
+    },
+
+    /// Delete archives according to the configured retention policy
+    Prune {
+        /// Only print which archives would be deleted
+        #[arg(long, action)]
+        dry_run: bool,
+    },
+}
+
+pub fn run_backup_cmd(backup_cmd: Backup, config: &Config) -> Result<()> {
+    let store = ArchiveStore::backups(&config.sync.cache_location);
+
+    match backup_cmd {
+        Backup::List => {
+            let archives = store.list()?;
+            if archives.is_empty() {
+                println!("No backups stored in {}", config.sync.cache_location);
+            }
+
+            for archive in archives {
+                print_archive(&archive);
+            }
+        }
+
+        Backup::Show { id } => {
+            let archive = if id == "latest" {
+                store.latest()?
+            } else {
+                store.get(&id)?
+            };
+            let archive = archive.ok_or_else(|| anyhow!("No backup found for '{}'", id))?;
+            let contents = TeleporterArchive::load(&archive.path)?;
+
+            print_archive(&archive);
+            println!("  Path: {}", archive.path.display());
+            println!("  Files:");
+            for (name, size) in contents.members() {
+                println!("    {} ({} bytes)", name, size);
+            }
+
+            println!("  Contents:");
+            let snapshot = Snapshot::from_archive(&contents, &SyncImportOptions::default())?;
+            for (section, count) in snapshot.entry_counts() {
+                println!("    {}: {}", section, count);
+            }
+        }
+
+        Backup::Prune { dry_run } => {
+            let removed = store.prune(&config.sync.backup_retention, dry_run)?;
+            let verb = if dry_run { "Would delete" } else { "Deleted" };
+
+            for archive in &removed {
+                println!("{} {}", verb, archive.id);
+            }
+            println!("{} {} backup(s)", verb, removed.len());
+        }
+    }
+
+    Ok(())
+}
+
+fn print_archive(archive: &StoredArchive) {
+    println!(
+        "{}  created {}  {} bytes",
+        archive.id,
+        archive.created.to_rfc3339(),
+        archive.size
+    );
+}
@@ -0,0 +1,237 @@
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use serde::Serialize;
+use tracing::{error, info, warn};
+
+use crate::{
+    config::{
+        Config, GravityStrategy, GravitySyncIncludes, RetryConfig, SyncImportOptions, SyncMode,
+    },
+    diff::{diff, Section, SectionDiff, Snapshot},
+    pihole_client::PiHoleClient,
+    retry::with_retry,
+    state::{Ledger, SyncState},
+    teleporter::TeleporterArchive,
+    transform,
+};
+
+#[derive(Debug, Clone, Copy, Default, ValueEnum)]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+#[derive(Debug, Serialize)]
+struct SecondaryDiff {
+    host: String,
+    identical: bool,
+    sections: Vec<SectionDiff>,
+    /// Why the secondary could not be compared
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct DiffReport {
+    identical: bool,
+    secondaries: Vec<SecondaryDiff>,
+}
+
+/// Compares the main instance with one secondary (or all enabled ones) without changing anything.
+/// Returns whether any secondary has drifted from main. Secondaries that could not be compared
+/// are reported and make it fail after the report is printed.
+pub async fn run_diff(config: &Config, host: Option<&str>, output: OutputFormat) -> Result<bool> {
+    let secondaries = match host {
+        Some(host) => vec![config
+            .secondary
+            .iter()
+            .find(|instance| instance.host == host)
+            .ok_or_else(|| anyhow!("No secondary instance found with hostname '{}'", host))?],
+        None => config
+            .secondary
+            .iter()
+            .filter(|instance| instance.enabled.unwrap_or(true))
+            .collect(),
+    };
+
+    let state = SyncState::load(&config.sync.cache_location)?;
+    let main_pihole = PiHoleClient::new(config.main.clone());
+    info!("Downloading backup from main instance...");
+    let main_archive = TeleporterArchive::from_bytes(
+        &with_retry(&config.sync.retry, "Downloading backup", || {
+            main_pihole.fetch_backup()
+        })
+        .await?,
+    )?;
+    main_pihole.logout().await?;
+
+    let mut report = DiffReport {
+        identical: true,
+        secondaries: Vec::new(),
+    };
+    let mut failed = Vec::new();
+
+    for secondary_config in secondaries {
+        let secondary_pihole = PiHoleClient::new(secondary_config.clone());
+        let ledger = state
+            .instance(&secondary_config.host)
+            .map(|instance| instance.ledger.clone())
+            .unwrap_or_default();
+        let result = diff_secondary(
+            &main_archive,
+            &secondary_pihole,
+            &config.sync.retry,
+            &ledger,
+        )
+        .await;
+        if let Err(e) = secondary_pihole.logout().await {
+            warn!("Failed to log out from {}: {:?}", secondary_config.host, e);
+        }
+
+        match result {
+            Ok(sections) => {
+                let identical = sections.iter().all(SectionDiff::is_empty);
+                report.identical &= identical;
+                report.secondaries.push(SecondaryDiff {
+                    host: secondary_config.host.clone(),
+                    identical,
+                    sections,
+                    error: None,
+                });
+            }
+            Err(e) => {
+                error!("Failed to compare {}: {:?}", secondary_config.host, e);
+                report.identical = false;
+                report.secondaries.push(SecondaryDiff {
+                    host: secondary_config.host.clone(),
+                    identical: false,
+                    sections: Vec::new(),
+                    error: Some(format!("{:#}", e)),
+                });
+                failed.push(secondary_config.host.clone());
+            }
+        }
+    }
+
+    match output {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&report)?),
+        OutputFormat::Text => {
+            for secondary in &report.secondaries {
+                if let Some(error) = &secondary.error {
+                    println!("{}: comparison failed: {}", secondary.host, error);
+                    println!();
+                    continue;
+                }
+                println!("{}:", secondary.host);
+                for section_diff in &secondary.sections {
+                    println!("{}", section_diff);
+                }
+                println!();
+            }
+        }
+    }
+
+    if !failed.is_empty() {
+        return Err(anyhow!("Failed to compare {}", failed.join(", ")));
+    }
+
+    Ok(!report.identical)
+}
+
+/// Downloads the secondary's archive and compares it with what a sync from main would upload.
+/// Config API secondaries are only compared on their configuration. With the merge gravity
+/// strategy, the secondary's own entries are not reported as drift. Groups a sync would create
+/// for the group assignments are reported as missing.
+pub(super) async fn diff_secondary(
+    main_archive: &TeleporterArchive,
+    secondary_pihole: &PiHoleClient,
+    retry_policy: &RetryConfig,
+    ledger: &Ledger,
+) -> Result<Vec<SectionDiff>> {
+    let instance = &secondary_pihole.config;
+    let mut import_options = match instance.mode.unwrap_or_default() {
+        SyncMode::Teleporter | SyncMode::Rest => {
+            instance.import_options.clone().unwrap_or_default()
+        }
+        SyncMode::ConfigApi => SyncImportOptions {
+            config: true,
+            dhcp_leases: false,
+            gravity: GravitySyncIncludes {
+                group: false,
+                adlist: false,
+                adlist_by_group: false,
+                domainlist: false,
+                domainlist_by_group: false,
+                client: false,
+                client_by_group: false,
+            },
+        },
+    };
+
+    info!("Downloading backup from {}...", instance.host);
+    let mut secondary_archive = TeleporterArchive::from_bytes(
+        &with_retry(
+            retry_policy,
+            &format!("Downloading backup from {}", instance.host),
+            || secondary_pihole.fetch_backup(),
+        )
+        .await?,
+    )?;
+
+    // The sync creates missing groups before it maps the assignments onto them
+    let missing_groups = match instance.mode.unwrap_or_default() {
+        SyncMode::Teleporter | SyncMode::Rest => {
+            transform::missing_groups(main_archive, &secondary_archive, instance)?
+        }
+        SyncMode::ConfigApi => Vec::new(),
+    };
+    let mut prepared_archive = secondary_archive.clone();
+    transform::add_groups(&mut prepared_archive, &missing_groups)?;
+
+    let expected_archive = transform::rewrite_archive(
+        main_archive,
+        &prepared_archive,
+        instance,
+        &mut ledger.clone(),
+    )?;
+
+    // The secondary's groups outside the scope are left alone by a REST sync
+    if instance.mode == Some(SyncMode::Rest) && instance.scopes_groups() {
+        transform::scope_groups(&mut secondary_archive, instance)?;
+    }
+
+    // Without the group table, only the groups the sync would create are compared
+    let compares_all_groups = import_options.gravity.group;
+    import_options.gravity.group |= !missing_groups.is_empty();
+
+    let mut main_snapshot = Snapshot::from_archive(
+        expected_archive.as_ref().unwrap_or(main_archive),
+        &import_options,
+    )?;
+    let mut secondary_snapshot = Snapshot::from_archive(&secondary_archive, &import_options)?;
+    if !compares_all_groups {
+        let is_missing = |name: &str| missing_groups.iter().any(|group| group.name == name);
+        main_snapshot.retain(Section::Groups, is_missing);
+        secondary_snapshot.retain(Section::Groups, is_missing);
+    }
+
+    if instance.mode == Some(SyncMode::Rest)
+        && instance.gravity_strategy == Some(GravityStrategy::Merge)
+    {
+        for (section, kind) in [
+            (Section::Groups, "group"),
+            (Section::Adlists, "adlist"),
+            (Section::Domains, "domain"),
+            (Section::Clients, "client"),
+        ] {
+            let owned = ledger.gravity.get(kind);
+            secondary_snapshot.retain(section, |key| {
+                main_snapshot.contains(section, key)
+                    || owned.is_some_and(|owned| owned.contains(key))
+            });
+        }
+    }
+
+    Ok(diff(&main_snapshot, &secondary_snapshot))
+}
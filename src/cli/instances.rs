@@ -1,4 +1,4 @@
-use clap::{arg, Subcommand};
+use clap::Subcommand;
 use tracing::info;
 
 use crate::config::{Config, InstanceConfig};
@@ -46,6 +46,11 @@ pub fn run_instances_cmd(
                     "  Update Gravity: {}",
                     instance.update_gravity.unwrap_or(false)
                 );
+                println!("  Enabled: {}", instance.enabled.unwrap_or(true));
+                println!(
+                    "  Interval: {}",
+                    instance.interval.as_ref().unwrap_or(&config.sync.interval)
+                );
                 println!();
             }
         }
@@ -65,6 +70,7 @@ pub fn run_instances_cmd(
                 api_key,
                 update_gravity: Some(update_gravity),
                 import_options: Some(crate::config::SyncImportOptions::default()),
+                ..Default::default()
             });
             config.save(config_path)?;
             info!("Instance added successfully!");
@@ -0,0 +1,68 @@
+use anyhow::{anyhow, Context, Result};
+use tracing::info;
+
+use crate::{
+    archive_store::ArchiveStore, config::Config, pihole_client::PiHoleClient, retry::with_retry,
+    state::SyncState,
+};
+
+/// Uploads a pre-sync snapshot back to a secondary. Uses the latest snapshot if no ID is given.
+pub async fn run_restore(config_path: &str, host: &str, snapshot_id: Option<String>) -> Result<()> {
+    let config = Config::load(config_path)?;
+
+    let instance = config
+        .secondary
+        .iter()
+        .find(|instance| instance.host == host)
+        .ok_or_else(|| anyhow!("No secondary instance found with hostname '{}'", host))?;
+
+    let store = ArchiveStore::snapshots(&config.sync.cache_location, host);
+    let snapshot = match &snapshot_id {
+        Some(id) => store.get(id)?,
+        None => store.latest()?,
+    };
+
+    let Some(snapshot) = snapshot else {
+        let available = store.list()?;
+        if available.is_empty() {
+            return Err(anyhow!("No snapshots found for {}", host));
+        }
+
+        println!("Available snapshots for {}:", host);
+        for snapshot in available {
+            println!(
+                "  {} (created {}, {} bytes)",
+                snapshot.id,
+                snapshot.created.to_rfc3339(),
+                snapshot.size
+            );
+        }
+        return Err(anyhow!(
+            "Snapshot '{}' not found for {}",
+            snapshot_id.unwrap_or_default(),
+            host
+        ));
+    };
+
+    info!("Restoring snapshot {} to {}", snapshot.id, host);
+
+    let pihole = PiHoleClient::new(instance.clone());
+    with_retry(
+        &config.sync.retry,
+        &format!("Uploading snapshot to {}", host),
+        || pihole.upload_backup(&snapshot.path),
+    )
+    .await
+    .with_context(|| format!("Failed to restore snapshot {} to {}", snapshot.id, host))?;
+
+    // The secondary no longer matches main, so the next sync must upload again
+    let mut state = SyncState::load(&config.sync.cache_location)?;
+    state.instance_mut(host).applied_hash = None;
+    state.save(&config.sync.cache_location)?;
+
+    pihole.logout().await?;
+
+    println!("✅ Restored snapshot {} to {}", snapshot.id, host);
+
+    Ok(())
+}
@@ -0,0 +1,58 @@
+use anyhow::Result;
+use chrono::{DateTime, Local, Utc};
+
+use crate::{config::Config, state::SyncState};
+
+/// Prints the persisted sync state of all configured instances
+pub fn run_status(config: &Config) -> Result<()> {
+    let state = SyncState::load(&config.sync.cache_location)?;
+
+    println!(
+        "{:<30} {:<9} {:<20} {:<20} {:>8} {:>10} {:<12} LAST ERROR",
+        "HOST", "ROLE", "LAST ATTEMPT", "LAST SUCCESS", "FAILURES", "DURATION", "HASH"
+    );
+
+    let instances =
+        std::iter::once((&config.main, "main")).chain(config.secondary.iter().map(|instance| {
+            let role = if instance.enabled.unwrap_or(true) {
+                "secondary"
+            } else {
+                "disabled"
+            };
+            (instance, role)
+        }));
+
+    for (instance, role) in instances {
+        let instance_state = state.instance(&instance.host).cloned().unwrap_or_default();
+
+        println!(
+            "{:<30} {:<9} {:<20} {:<20} {:>8} {:>10} {:<12} {}",
+            instance.host,
+            role,
+            format_time(instance_state.last_attempt),
+            format_time(instance_state.last_success),
+            instance_state.consecutive_failures,
+            instance_state
+                .last_duration_ms
+                .map(|ms| format!("{:.1}s", ms as f64 / 1000.0))
+                .unwrap_or_else(|| "-".to_string()),
+            instance_state
+                .applied_hash
+                .as_deref()
+                .map(|hash| &hash[..12.min(hash.len())])
+                .unwrap_or("-"),
+            instance_state.last_error.as_deref().unwrap_or("-"),
+        );
+    }
+
+    Ok(())
+}
+
+fn format_time(time: Option<DateTime<Utc>>) -> String {
+    time.map(|time| {
+        time.with_timezone(&Local)
+            .format("%Y-%m-%d %H:%M:%S")
+            .to_string()
+    })
+    .unwrap_or_else(|| "never".to_string())
+}
@@ -1,19 +1,50 @@
-use std::{fs, path::Path, time::Duration};
+mod config_api;
+mod gravity_api;
 
-use tokio::time::sleep;
-use tracing::{error, info};
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex as StdMutex},
+    time::{Duration, Instant},
+};
 
-use crate::{config::Config, pihole_client::PiHoleClient};
-use anyhow::Result;
+use chrono::{DateTime, Local, Utc};
+use config_api::sync_config_api;
+use gravity_api::sync_rest;
 
-pub async fn run_sync(config_path: &str, run_once: bool) -> Result<()> {
+use super::diff::diff_secondary;
+use serde_json::Value;
+use tokio::{
+    sync::{Mutex, Semaphore, SemaphorePermit},
+    task::JoinSet,
+    time::{sleep, sleep_until, timeout},
+};
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    archive_store::ArchiveStore,
+    config::{Config, GravityConfig, RetryConfig, SyncConfig, SyncImportOptions, SyncMode},
+    diff::{diff, Section, Snapshot},
+    gravity::{Gravity, GravityEntry},
+    pihole_client::PiHoleClient,
+    retry::with_retry,
+    schedule::{Schedule, SlotJitter},
+    state::{Ledger, SyncState},
+    teleporter::TeleporterArchive,
+    transform,
+};
+use anyhow::{anyhow, Context, Result};
+
+/// Upper bound for a gravity run if `gravity.timeout` is not set
+const DEFAULT_GRAVITY_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+pub async fn run_sync(config_path: &str, run_once: bool, force: bool) -> Result<()> {
     // Load config
     let config = Config::load(config_path)?;
-    let sync_interval = Duration::from_secs(config.sync.interval * 60);
-    let backup_path = Path::new(&config.sync.cache_location).join("pihole_backup.zip");
 
     // Check cache directory
-    info!("Checking cache directory: {}", backup_path.display());
+    info!("Checking cache directory: {}", config.sync.cache_location);
     let path = Path::new(&config.sync.cache_location);
 
     if !path.exists() {
@@ -30,64 +61,972 @@ pub async fn run_sync(config_path: &str, run_once: bool) -> Result<()> {
 
     let main_pihole = PiHoleClient::new(config.main.clone());
 
-    let secondary_piholes = config
-        .secondary
-        .iter()
-        .map(|secondary_config| PiHoleClient::new(secondary_config.clone()))
-        .collect::<Vec<_>>();
+    let mut secondaries = Vec::new();
+    for secondary_config in &config.secondary {
+        if !secondary_config.enabled.unwrap_or(true) {
+            info!("Skipping disabled instance {}", secondary_config.host);
+            continue;
+        }
+
+        secondaries.push(ScheduledSecondary {
+            pihole: PiHoleClient::new(secondary_config.clone()),
+            schedule: secondary_config
+                .interval
+                .clone()
+                .unwrap_or_else(|| config.sync.interval.clone()),
+            next_run: Some(Utc::now()),
+        });
+    }
+
+    if secondaries.is_empty() {
+        warn!("No enabled secondary instances configured. Nothing to sync.");
+        return Ok(());
+    }
 
     if !run_once {
+        // The main instance is needed whenever any secondary is due
+        let shortest_period = secondaries
+            .iter()
+            .map(|secondary| secondary.schedule.approximate_period())
+            .min()
+            .unwrap_or_default();
         main_pihole
-            .init_session_keepalive(sync_interval.as_secs())
+            .init_session_keepalive(shortest_period.as_secs())
             .await?;
 
-        for secondary_pihole in &secondary_piholes {
-            secondary_pihole
-                .init_session_keepalive(sync_interval.as_secs())
+        for secondary in &secondaries {
+            secondary
+                .pihole
+                .init_session_keepalive(secondary.schedule.approximate_period().as_secs())
                 .await?;
         }
     }
 
+    if force {
+        info!("--force specified. Uploading to all secondaries regardless of changes.");
+    }
+
+    let runner = Arc::new(SyncRunner {
+        main_pihole: main_pihole.clone(),
+        semaphore: Arc::new(Semaphore::new(config.sync.max_parallel.max(1))),
+        gravity_gate: Arc::new(GravityGate::new(&config.gravity)),
+        state: StateStore::new(&config.sync.cache_location),
+        syncing: StdMutex::new(HashSet::new()),
+        force,
+        config,
+    });
+
     info!("Running in sync mode...");
+    let mut jitter = SlotJitter::new(runner.config.sync.jitter);
+    for secondary in &mut secondaries {
+        info!("{}: {}", secondary.pihole.config.host, secondary.schedule);
+        if !run_once && !runner.config.sync.run_on_start {
+            secondary.next_run = jitter.next_run(&secondary.schedule, Utc::now());
+        }
+    }
+
     loop {
-        info!("Downloading backup from main instance...");
-        if let Err(e) = main_pihole.download_backup(&backup_path).await {
-            error!("Failed to download backup: {:?}", e);
-        } else {
-            for secondary_pihole in &secondary_piholes {
-                info!("Uploading backup to {}", secondary_pihole.config.host);
-                if let Err(e) = secondary_pihole.upload_backup(&backup_path).await {
-                    error!(
-                        "Failed to upload backup to {}: {:?}",
-                        secondary_pihole.config.host, e
-                    );
-                    continue;
-                } else if secondary_pihole.config.update_gravity.unwrap_or(false) {
-                    info!("Updating gravity on {}", secondary_pihole.config.host);
-                    if let Err(e) = secondary_pihole.trigger_gravity_update().await {
-                        error!(
-                            "Failed to update gravity on {}: {:?}",
-                            secondary_pihole.config.host, e
-                        );
-                    }
-                }
-            }
+        if !run_once {
+            wait_for_next_run(&secondaries).await;
         }
 
+        let now = Utc::now();
+        let due = secondaries
+            .iter()
+            .filter(|secondary| run_once || secondary.is_due(now))
+            .filter_map(|secondary| runner.claim(&secondary.pihole))
+            .collect::<Vec<_>>();
+
         if run_once {
+            run_cycle(runner.clone(), due).await;
             info!("Sync complete. Exiting because --once was specified.");
             main_pihole.logout().await?;
-            for secondary in &secondary_piholes {
-                secondary.logout().await?;
+            for secondary in &secondaries {
+                secondary.pihole.logout().await?;
             }
             return Ok(());
         }
 
+        // The cycle runs in the background, so a slow secondary does not hold back the
+        // secondaries due next
+        if !due.is_empty() {
+            tokio::spawn(run_cycle(runner.clone(), due));
+        }
+
+        for secondary in secondaries
+            .iter_mut()
+            .filter(|secondary| secondary.is_due(now))
+        {
+            secondary.next_run = jitter.next_run(&secondary.schedule, Utc::now());
+            if secondary.next_run.is_none() {
+                warn!(
+                    "{}: {} does not run anymore",
+                    secondary.pihole.config.host, secondary.schedule
+                );
+            }
+        }
+    }
+}
+
+/// A secondary instance with its own schedule
+struct ScheduledSecondary {
+    pihole: PiHoleClient,
+    schedule: Schedule,
+    /// `None` once the schedule does not fire anymore
+    next_run: Option<DateTime<Utc>>,
+}
+
+impl ScheduledSecondary {
+    fn is_due(&self, now: DateTime<Utc>) -> bool {
+        self.next_run.is_some_and(|next_run| next_run <= now)
+    }
+}
+
+/// Everything the sync cycles share while the sync is running
+struct SyncRunner {
+    main_pihole: PiHoleClient,
+    config: Config,
+    semaphore: Arc<Semaphore>,
+    gravity_gate: Arc<GravityGate>,
+    state: StateStore,
+    /// Hosts whose sync has not finished yet
+    syncing: StdMutex<HashSet<String>>,
+    force: bool,
+}
+
+impl SyncRunner {
+    /// Marks the secondary as syncing. Returns `None` if its previous sync is still running.
+    fn claim(self: &Arc<Self>, pihole: &PiHoleClient) -> Option<Claim> {
+        let host = &pihole.config.host;
+        let mut syncing = self.syncing.lock().expect("syncing lock is never poisoned");
+        if !syncing.insert(host.clone()) {
+            warn!(
+                "Previous sync of {} is still running. Skipping this run.",
+                host
+            );
+            return None;
+        }
+
+        Some(Claim {
+            pihole: pihole.clone(),
+            runner: self.clone(),
+        })
+    }
+}
+
+/// A secondary that is syncing. It may be synced again once the claim is dropped.
+struct Claim {
+    pihole: PiHoleClient,
+    runner: Arc<SyncRunner>,
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        self.runner
+            .syncing
+            .lock()
+            .expect("syncing lock is never poisoned")
+            .remove(&self.pihole.config.host);
+    }
+}
+
+/// Sync state shared by concurrent cycles. Every update reloads the state file, other
+/// cycles and commands (e.g. restore) may have changed it. An unreadable state file is never
+/// overwritten, it holds the ledgers of what the sync owns on each secondary.
+struct StateStore {
+    cache_location: String,
+    lock: Mutex<()>,
+}
+
+impl StateStore {
+    fn new(cache_location: &str) -> Self {
+        Self {
+            cache_location: cache_location.to_string(),
+            lock: Mutex::new(()),
+        }
+    }
+
+    fn load(&self) -> Result<SyncState> {
+        SyncState::load(&self.cache_location)
+    }
+
+    async fn update(&self, update: impl FnOnce(&mut SyncState)) {
+        let _lock = self.lock.lock().await;
+        let mut state = match self.load() {
+            Ok(state) => state,
+            Err(e) => {
+                error!(
+                    "Failed to load sync state, the result is not recorded: {:?}",
+                    e
+                );
+                return;
+            }
+        };
+        update(&mut state);
+        if let Err(e) = state.save(&self.cache_location) {
+            error!("Failed to save sync state: {:?}", e);
+        }
+    }
+}
+
+/// Downloads the main archive once, syncs it to all due secondaries and records the outcome
+async fn run_cycle(runner: Arc<SyncRunner>, due: Vec<Claim>) {
+    let config = &runner.config;
+    let main_pihole = &runner.main_pihole;
+    let secondary_timeout = Duration::from_secs(config.sync.secondary_timeout);
+    let state = match runner.state.load() {
+        Ok(state) => state,
+        Err(e) => {
+            error!(
+                "Failed to load sync state, skipping this sync. Repair or remove the file to continue: {:?}",
+                e
+            );
+            return;
+        }
+    };
+
+    info!("Downloading backup from main instance...");
+    let started = Instant::now();
+    let backup = with_retry(&config.sync.retry, "Downloading backup", || {
+        main_pihole.fetch_backup()
+    })
+    .await
+    .and_then(|bytes| store_main_backup(&bytes, &config.sync));
+
+    let (backup_path, main_archive) = match backup {
+        Ok(backup) => {
+            runner
+                .state
+                .update(|state| {
+                    state
+                        .instance_mut(&main_pihole.config.host)
+                        .record_success(started.elapsed())
+                })
+                .await;
+            backup
+        }
+        Err(e) => {
+            error!("Failed to download backup: {:?}", e);
+            runner
+                .state
+                .update(|state| {
+                    state
+                        .instance_mut(&main_pihole.config.host)
+                        .record_failure(&e, started.elapsed())
+                })
+                .await;
+            return;
+        }
+    };
+
+    let uses_mode = |mode: SyncMode| {
+        due.iter()
+            .any(|secondary| secondary.pihole.config.mode.unwrap_or_default() == mode)
+    };
+
+    let main_config = if uses_mode(SyncMode::ConfigApi) || uses_mode(SyncMode::Rest) {
+        info!("Fetching configuration from main instance...");
+        with_retry(&config.sync.retry, "Fetching main configuration", || {
+            main_pihole.get_config()
+        })
+        .await
+        .map_err(|e| error!("Failed to fetch main configuration: {:?}", e))
+        .ok()
+    } else {
+        None
+    };
+
+    let main_gravity = if uses_mode(SyncMode::Rest) {
+        info!("Fetching gravity from main instance...");
+        with_retry(&config.sync.retry, "Fetching main gravity", || {
+            main_pihole.get_gravity()
+        })
+        .await
+        .map_err(|e| error!("Failed to fetch main gravity: {:?}", e))
+        .ok()
+    } else {
+        None
+    };
+
+    let context = Arc::new(SyncContext {
+        sync_config: config.sync.clone(),
+        backup_path,
+        main_archive,
+        main_config,
+        main_gravity,
+        gravity_gate: runner.gravity_gate.clone(),
+        gravity_timeout: config.gravity.timeout.unwrap_or(DEFAULT_GRAVITY_TIMEOUT),
+    });
+
+    let mut tasks = JoinSet::new();
+    let mut outcomes = Vec::new();
+
+    for claim in due {
+        let host = claim.pihole.config.host.clone();
+        let archive_hash = context.main_archive.as_ref().and_then(|archive| {
+            transform::archive_hash(archive, &claim.pihole.config)
+                .map_err(|e| warn!("Failed to hash backup archive for {}: {:?}", host, e))
+                .ok()
+        });
+
+        let applied_hash = state
+            .instance(&host)
+            .and_then(|instance| instance.applied_hash.as_ref());
+        let is_teleporter = claim.pihole.config.mode.unwrap_or_default() == SyncMode::Teleporter;
+
+        if is_teleporter
+            && !runner.force
+            && archive_hash.is_some()
+            && archive_hash.as_ref() == applied_hash
+        {
+            info!("No changes for {} since last sync. Skipping upload.", host);
+            let outcome = SecondaryOutcome {
+                ledger: state
+                    .instance(&host)
+                    .map(|instance| instance.ledger.clone())
+                    .unwrap_or_default(),
+                host,
+                archive_hash,
+                result: Ok(SyncAction::Unchanged),
+                duration: Duration::ZERO,
+            };
+            runner
+                .state
+                .update(|state| record_outcome(state, &outcome))
+                .await;
+            outcomes.push(outcome);
+            continue;
+        }
+
+        let semaphore = runner.semaphore.clone();
+        let context = context.clone();
+        let mut ledger = state
+            .instance(&host)
+            .map(|instance| instance.ledger.clone())
+            .unwrap_or_default();
+
+        tasks.spawn(async move {
+            let secondary_pihole = &claim.pihole;
+            let permit = semaphore.acquire_owned().await;
+            let started = Instant::now();
+
+            let result = match timeout(
+                secondary_timeout,
+                sync_secondary(secondary_pihole, &context, &mut ledger),
+            )
+            .await
+            {
+                Ok(result) => result,
+                Err(_) => Err(anyhow!(
+                    "Sync did not finish within {} seconds",
+                    secondary_timeout.as_secs()
+                )),
+            };
+
+            // Gravity waits for its slot and runs outside of the sync's timeout. Other
+            // secondaries may sync in the meantime.
+            drop(permit);
+            let result = match result {
+                Ok(Synced {
+                    action,
+                    gravity: Some(update),
+                }) => run_gravity_update(secondary_pihole, &context, update, &mut ledger)
+                    .await
+                    .map(|_| action),
+                Ok(synced) => Ok(synced.action),
+                Err(e) => Err(e),
+            };
+
+            SecondaryOutcome {
+                host,
+                archive_hash,
+                result,
+                duration: started.elapsed(),
+                ledger,
+            }
+        });
+    }
+
+    // Every outcome is recorded as soon as it is known, a later secondary may take long
+    while let Some(joined) = tasks.join_next().await {
+        match joined {
+            Ok(outcome) => {
+                runner
+                    .state
+                    .update(|state| record_outcome(state, &outcome))
+                    .await;
+                outcomes.push(outcome);
+            }
+            Err(e) => error!("Sync task failed: {:?}", e),
+        }
+    }
+
+    print_summary(&outcomes);
+}
+
+/// Records a secondary's sync result and ledger in the state
+fn record_outcome(state: &mut SyncState, outcome: &SecondaryOutcome) {
+    let instance_state = state.instance_mut(&outcome.host);
+    match &outcome.result {
+        Ok(action) => {
+            instance_state.record_success(outcome.duration);
+            instance_state.ledger = outcome.ledger.clone();
+            if let SyncAction::Applied = action {
+                instance_state.applied_hash = outcome.archive_hash.clone();
+            }
+        }
+        Err(e) => {
+            instance_state.record_failure(e, outcome.duration);
+            // Gravity ownership is recorded per applied operation, so it is accurate
+            // even if the sync failed halfway
+            instance_state.ledger.gravity = outcome.ledger.gravity.clone();
+            // The patch may have been applied before the failure. Recording too many
+            // records as main's is harmless in a merge, missing ones would never be removed.
+            for (path, records) in &outcome.ledger.dns_records {
+                instance_state
+                    .ledger
+                    .dns_records
+                    .entry(path.clone())
+                    .or_default()
+                    .extend(records.iter().cloned());
+            }
+        }
+    }
+}
+
+/// Sleeps until the earliest scheduled run of any secondary, or forever if none is left
+async fn wait_for_next_run(secondaries: &[ScheduledSecondary]) {
+    let Some(next_run) = secondaries
+        .iter()
+        .filter_map(|secondary| secondary.next_run)
+        .min()
+    else {
+        warn!("No scheduled syncs left");
+        return std::future::pending().await;
+    };
+
+    let hosts = secondaries
+        .iter()
+        .filter(|secondary| secondary.next_run == Some(next_run))
+        .map(|secondary| secondary.pihole.config.host.as_str())
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    info!(
+        "Next sync at {} ({})",
+        next_run.with_timezone(&Local).to_rfc3339(),
+        hosts
+    );
+    sleep((next_run - Utc::now()).to_std().unwrap_or_default()).await;
+}
+
+/// Adds the main instance's archive to the backup history and applies the retention policy.
+/// Returns the path of the stored archive and its parsed contents, if readable.
+fn store_main_backup(
+    bytes: &[u8],
+    sync_config: &SyncConfig,
+) -> Result<(PathBuf, Option<TeleporterArchive>)> {
+    let store = ArchiveStore::backups(&sync_config.cache_location);
+
+    let main_archive = match TeleporterArchive::from_bytes(bytes) {
+        Ok(archive) => Some(archive),
+        Err(e) => {
+            warn!("Failed to read backup archive, change detection and verification disabled for this run: {:?}", e);
+            None
+        }
+    };
+
+    let content_hash = main_archive.as_ref().and_then(|archive| {
+        archive
+            .content_hash(&SyncImportOptions::default())
+            .map_err(|e| warn!("Failed to hash backup archive: {:?}", e))
+            .ok()
+    });
+
+    let stored = match content_hash {
+        Some(content_hash) => {
+            let (stored, is_new) = store.save_content(bytes, &content_hash)?;
+            if is_new {
+                info!("Stored new backup {}", stored.id);
+            } else {
+                info!("Main archive unchanged since backup {}", stored.id);
+            }
+            stored
+        }
+        None => store.save(bytes)?,
+    };
+
+    for removed in store.prune(&sync_config.backup_retention, false)? {
+        debug!("Pruned backup {}", removed.id);
+    }
+
+    Ok((stored.path, main_archive))
+}
+
+/// Compares the main archive with every secondary's archive and prints the differences
+/// an upload would apply. Nothing is uploaded.
+pub async fn run_dry_run(config_path: &str) -> Result<()> {
+    let config = Config::load(config_path)?;
+    let retry_policy = &config.sync.retry;
+    let state = SyncState::load(&config.sync.cache_location)?;
+    let main_pihole = PiHoleClient::new(config.main.clone());
+
+    info!("Dry run: downloading backup from main instance...");
+    let main_archive = TeleporterArchive::from_bytes(
+        &with_retry(retry_policy, "Downloading backup", || {
+            main_pihole.fetch_backup()
+        })
+        .await?,
+    )?;
+
+    for secondary_config in &config.secondary {
+        if !secondary_config.enabled.unwrap_or(true) {
+            info!(
+                "Dry run: skipping disabled instance {}",
+                secondary_config.host
+            );
+            continue;
+        }
+
+        let secondary_pihole = PiHoleClient::new(secondary_config.clone());
+        let host = &secondary_config.host;
+
+        let ledger = state
+            .instance(host)
+            .map(|instance| instance.ledger.clone())
+            .unwrap_or_default();
+
+        match diff_secondary(&main_archive, &secondary_pihole, retry_policy, &ledger).await {
+            Ok(section_diffs) => {
+                println!("{}:", host);
+                for section_diff in section_diffs {
+                    println!("{}", section_diff);
+                }
+                println!();
+            }
+            Err(e) => error!("Failed to compare {}: {:?}", host, e),
+        }
+
+        secondary_pihole.logout().await?;
+    }
+
+    main_pihole.logout().await?;
+
+    Ok(())
+}
+
+/// Data shared by the sync tasks of one cycle
+struct SyncContext {
+    sync_config: SyncConfig,
+    backup_path: PathBuf,
+    main_archive: Option<TeleporterArchive>,
+    /// Configuration of the main instance, only fetched if a due secondary uses the Config API
+    main_config: Option<Value>,
+    /// Gravity of the main instance, only fetched if a due secondary uses the REST mode
+    main_gravity: Option<Gravity>,
+    gravity_gate: Arc<GravityGate>,
+    gravity_timeout: Duration,
+}
+
+/// Limits how many secondaries run gravity at the same time and spaces out the starts of
+/// their runs, so they do not all download the same blocklists at once
+struct GravityGate {
+    slots: Semaphore,
+    delay: Duration,
+    next_start: Mutex<Instant>,
+}
+
+impl GravityGate {
+    fn new(config: &GravityConfig) -> Self {
+        Self {
+            slots: Semaphore::new(
+                config
+                    .max_concurrent
+                    .unwrap_or(Semaphore::MAX_PERMITS)
+                    .max(1),
+            ),
+            delay: config.delay.unwrap_or_default(),
+            next_start: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Waits until a gravity run may start. The run has to finish before the permit is dropped.
+    async fn enter(&self) -> SemaphorePermit<'_> {
+        let permit = self
+            .slots
+            .acquire()
+            .await
+            .expect("gravity semaphore is never closed");
+
+        let mut next_start = self.next_start.lock().await;
+        sleep_until((*next_start).into()).await;
+        *next_start = Instant::now() + self.delay;
+
+        permit
+    }
+}
+
+enum SyncAction {
+    Applied,
+    Unchanged,
+}
+
+/// Result of a secondary's sync, with the gravity update it still needs
+struct Synced {
+    action: SyncAction,
+    gravity: Option<GravityUpdate>,
+}
+
+/// A gravity run due on a secondary because its adlists changed
+struct GravityUpdate {
+    /// Fingerprint of the adlists gravity runs with, `None` if they are unknown
+    adlists_hash: Option<String>,
+}
+
+struct SecondaryOutcome {
+    host: String,
+    archive_hash: Option<String>,
+    result: Result<SyncAction>,
+    duration: Duration,
+    /// Ledger of the instance after the sync. Only partly persisted if the sync failed.
+    ledger: Ledger,
+}
+
+async fn sync_secondary(
+    secondary_pihole: &PiHoleClient,
+    context: &SyncContext,
+    ledger: &mut Ledger,
+) -> Result<Synced> {
+    match secondary_pihole.config.mode.unwrap_or_default() {
+        SyncMode::Teleporter => sync_teleporter(secondary_pihole, context, ledger).await,
+        SyncMode::ConfigApi => Ok(Synced {
+            action: sync_config_api(
+                secondary_pihole,
+                context,
+                ledger,
+                &mut SnapshotGuard::default(),
+            )
+            .await?,
+            gravity: None,
+        }),
+        SyncMode::Rest => sync_rest(secondary_pihole, context, ledger).await,
+    }
+}
+
+/// Snapshots the secondary, uploads the backup, verifies the import and checks whether gravity
+/// needs an update.
+async fn sync_teleporter(
+    secondary_pihole: &PiHoleClient,
+    context: &SyncContext,
+    ledger: &mut Ledger,
+) -> Result<Synced> {
+    let host = &secondary_pihole.config.host;
+    let retry_policy = &context.sync_config.retry;
+
+    let snapshot = snapshot_secondary(secondary_pihole, context).await?;
+
+    let rewritten = match &context.main_archive {
+        Some(main_archive) => transform::rewrite_archive(
+            main_archive,
+            &create_missing_groups(
+                secondary_pihole,
+                context,
+                main_archive,
+                TeleporterArchive::from_bytes(&snapshot)?,
+            )
+            .await?,
+            &secondary_pihole.config,
+            ledger,
+        )?,
+        None if transform::rewrites_archive(&secondary_pihole.config) => {
+            return Err(anyhow!(
+                "Main archive is unreadable, cannot build the pihole.toml for {}",
+                host
+            ))
+        }
+        None => None,
+    };
+
+    let upload_path = match &rewritten {
+        Some(archive) => {
+            let uploads_dir = Path::new(&context.sync_config.cache_location).join("uploads");
+            fs::create_dir_all(&uploads_dir)
+                .with_context(|| format!("Failed to create {}", uploads_dir.display()))?;
+            let path = uploads_dir.join(format!("{}.zip", host));
+            fs::write(&path, archive.to_bytes()?)
+                .with_context(|| format!("Failed to write {}", path.display()))?;
+            path
+        }
+        None => context.backup_path.clone(),
+    };
+
+    info!("Uploading backup to {}", host);
+    with_retry(
+        retry_policy,
+        &format!("Uploading backup to {}", host),
+        || secondary_pihole.upload_backup(&upload_path),
+    )
+    .await
+    .with_context(|| format!("Failed to upload backup to {}", host))?;
+
+    if let Some(expected_archive) = rewritten.as_ref().or(context.main_archive.as_ref()) {
+        verify_import(secondary_pihole, expected_archive, retry_policy).await?;
+    } else {
+        warn!(
+            "Main archive unavailable. Skipping import verification for {}",
+            host
+        );
+    }
+
+    // The secondary's adlists after the import: the uploaded ones, or its own if not imported
+    let own_archive;
+    let adlists_source = if secondary_pihole
+        .config
+        .import_options
+        .clone()
+        .unwrap_or_default()
+        .gravity
+        .adlist
+    {
+        rewritten.as_ref().or(context.main_archive.as_ref())
+    } else {
+        own_archive = TeleporterArchive::from_bytes(&snapshot).ok();
+        own_archive.as_ref()
+    };
+    let adlists = adlists_source.and_then(|archive| archive.gravity().ok().flatten());
+
+    Ok(Synced {
+        action: SyncAction::Applied,
+        gravity: gravity_update(secondary_pihole, adlists.as_ref(), ledger),
+    })
+}
+
+/// Creates main's groups the secondary lacks, if the archive's group assignments are mapped to
+/// the secondary's groups by name. Returns the secondary's archive with the new groups.
+async fn create_missing_groups(
+    secondary_pihole: &PiHoleClient,
+    context: &SyncContext,
+    main_archive: &TeleporterArchive,
+    secondary_archive: TeleporterArchive,
+) -> Result<TeleporterArchive> {
+    let host = &secondary_pihole.config.host;
+    let missing =
+        transform::missing_groups(main_archive, &secondary_archive, &secondary_pihole.config)?;
+    if missing.is_empty() {
+        return Ok(secondary_archive);
+    }
+
+    for group in missing {
+        info!("Creating group '{}' on {}", group.name, host);
+        let entry = GravityEntry::Group(group);
+        with_retry(
+            &context.sync_config.retry,
+            &format!("Creating group '{}' on {}", entry.key(), host),
+            || secondary_pihole.add_gravity_entry(&entry),
+        )
+        .await
+        .with_context(|| format!("Failed to create group '{}' on {}", entry.key(), host))?;
+    }
+
+    // The assignments are translated to the IDs the new groups got
+    TeleporterArchive::from_bytes(
+        &with_retry(
+            &context.sync_config.retry,
+            &format!("Downloading backup from {}", host),
+            || secondary_pihole.fetch_backup(),
+        )
+        .await
+        .with_context(|| format!("Failed to download backup from {}", host))?,
+    )
+}
+
+/// Gravity update needed if `update_gravity` is enabled and the secondary's adlists changed
+/// since its last successful gravity update. Gravity always runs if the adlists are unknown.
+fn gravity_update(
+    secondary_pihole: &PiHoleClient,
+    gravity: Option<&Gravity>,
+    ledger: &Ledger,
+) -> Option<GravityUpdate> {
+    if !secondary_pihole.config.update_gravity.unwrap_or(false) {
+        return None;
+    }
+
+    let adlists_hash = gravity.map(Gravity::adlists_hash);
+    if adlists_hash.is_some() && adlists_hash == ledger.adlists_hash {
         info!(
-            "Sync complete. Sleeping for {} minutes...",
-            sync_interval.as_secs() / 60
+            "Adlists of {} unchanged since the last gravity update. Skipping gravity update.",
+            secondary_pihole.config.host
         );
+        return None;
+    }
+
+    Some(GravityUpdate { adlists_hash })
+}
+
+/// Runs gravity on the secondary once the gravity gate lets it, so runs across secondaries are
+/// limited and staggered. The slot is held until the run finished or hit `gravity.timeout`.
+async fn run_gravity_update(
+    secondary_pihole: &PiHoleClient,
+    context: &SyncContext,
+    update: GravityUpdate,
+    ledger: &mut Ledger,
+) -> Result<()> {
+    let host = &secondary_pihole.config.host;
+
+    let _slot = context.gravity_gate.enter().await;
+    info!("Updating gravity on {}", host);
+    timeout(
+        context.gravity_timeout,
+        with_retry(
+            &context.sync_config.retry,
+            &format!("Updating gravity on {}", host),
+            || secondary_pihole.run_gravity(),
+        ),
+    )
+    .await
+    .map_err(|_| {
+        anyhow!(
+            "Gravity did not finish within {} seconds",
+            context.gravity_timeout.as_secs()
+        )
+    })
+    .and_then(|result| result)
+    .with_context(|| format!("Failed to update gravity on {}", host))?;
+
+    ledger.adlists_hash = update.adlists_hash;
+    Ok(())
+}
+
+/// Saves the secondary's current archive before it gets overwritten, so it can be restored later.
+/// Returns the downloaded archive.
+async fn snapshot_secondary(
+    secondary_pihole: &PiHoleClient,
+    context: &SyncContext,
+) -> Result<Vec<u8>> {
+    let host = &secondary_pihole.config.host;
+
+    info!("Creating snapshot of {}", host);
+    let bytes = with_retry(
+        &context.sync_config.retry,
+        &format!("Downloading snapshot from {}", host),
+        || secondary_pihole.fetch_backup(),
+    )
+    .await
+    .with_context(|| format!("Failed to create snapshot of {}", host))?;
+
+    let store = ArchiveStore::snapshots(&context.sync_config.cache_location, host);
+    let snapshot = store.save(&bytes)?;
+    info!("Saved snapshot {} of {}", snapshot.id, host);
 
-        sleep(sync_interval).await;
+    for removed in store.keep_last(context.sync_config.snapshot_retention.max(1))? {
+        debug!("Removed old snapshot {} of {}", removed.id, host);
+    }
+
+    Ok(bytes)
+}
+
+/// Takes the snapshot of a secondary before the first change of a sync. Later changes of the
+/// same sync reuse it, so the newest snapshot always holds the state from before the sync.
+#[derive(Default)]
+struct SnapshotGuard {
+    taken: bool,
+}
+
+impl SnapshotGuard {
+    async fn ensure(
+        &mut self,
+        secondary_pihole: &PiHoleClient,
+        context: &SyncContext,
+    ) -> Result<()> {
+        if !self.taken {
+            snapshot_secondary(secondary_pihole, context).await?;
+            self.taken = true;
+        }
+        Ok(())
+    }
+}
+
+/// Re-downloads the secondary's archive and checks that every imported section matches
+/// the uploaded archive. Config keys missing from the uploaded archive are not checked.
+async fn verify_import(
+    secondary_pihole: &PiHoleClient,
+    expected_archive: &TeleporterArchive,
+    retry_policy: &RetryConfig,
+) -> Result<()> {
+    let host = &secondary_pihole.config.host;
+    let import_options = secondary_pihole
+        .config
+        .import_options
+        .clone()
+        .unwrap_or_default();
+
+    info!("Verifying import on {}", host);
+    let bytes = with_retry(
+        retry_policy,
+        &format!("Downloading backup from {} for verification", host),
+        || secondary_pihole.fetch_backup(),
+    )
+    .await
+    .with_context(|| format!("Failed to verify import on {}", host))?;
+    let secondary_archive = TeleporterArchive::from_bytes(&bytes)?;
+
+    let diverged = diff(
+        &Snapshot::from_archive(expected_archive, &import_options)?,
+        &Snapshot::from_archive(&secondary_archive, &import_options)?,
+    )
+    .into_iter()
+    .map(|mut section_diff| {
+        // Config keys only the secondary has (e.g. added by a newer FTL version) were not uploaded
+        if section_diff.section == Section::Config {
+            section_diff.removed.clear();
+        }
+        section_diff
+    })
+    .filter(|section_diff| !section_diff.is_empty())
+    .collect::<Vec<_>>();
+
+    if diverged.is_empty() {
+        info!("Import verified on {}", host);
+        return Ok(());
+    }
+
+    for section_diff in &diverged {
+        warn!("{}: {}", host, section_diff);
+    }
+
+    Err(anyhow!(
+        "Import verification failed on {}. Diverged sections: {}",
+        host,
+        diverged
+            .iter()
+            .map(|section_diff| format!(
+                "{} ({} changed, {} missing, {} unexpected)",
+                section_diff.section,
+                section_diff.changed.len(),
+                section_diff.added.len(),
+                section_diff.removed.len()
+            ))
+            .collect::<Vec<_>>()
+            .join(", ")
+    ))
+}
+
+fn print_summary(outcomes: &[SecondaryOutcome]) {
+    info!("Sync summary:");
+    for outcome in outcomes {
+        match &outcome.result {
+            Ok(SyncAction::Applied) => info!(
+                "  {}: success ({:.1}s)",
+                outcome.host,
+                outcome.duration.as_secs_f64()
+            ),
+            Ok(SyncAction::Unchanged) => info!("  {}: unchanged, skipped", outcome.host),
+            Err(e) => error!(
+                "  {}: failed ({:.1}s): {:#}",
+                outcome.host,
+                outcome.duration.as_secs_f64(),
+                e
+            ),
+        }
     }
 }
@@ -0,0 +1,151 @@
+use anyhow::{anyhow, Context, Result};
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+use super::{SnapshotGuard, SyncAction, SyncContext};
+use crate::{
+    config::RetryConfig,
+    pihole_client::PiHoleClient,
+    pihole_config::{
+        changed_entries, flatten, parse_detailed, unflatten, ConfigMap, DetailedConfig,
+    },
+    retry::with_retry,
+    state::Ledger,
+    transform::desired_config,
+};
+
+/// Upper bound for an instance to answer again after restarting its DNS resolver
+const RESTART_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Patches the config keys that differ from main through the Config API and verifies the result.
+pub(super) async fn sync_config_api(
+    secondary_pihole: &PiHoleClient,
+    context: &SyncContext,
+    ledger: &mut Ledger,
+    snapshot: &mut SnapshotGuard,
+) -> Result<SyncAction> {
+    let host = &secondary_pihole.config.host;
+    let retry_policy = &context.sync_config.retry;
+
+    let main_config = context
+        .main_config
+        .as_ref()
+        .ok_or_else(|| anyhow!("Configuration of the main instance is unavailable"))?;
+
+    let DetailedConfig {
+        values: secondary_config,
+        restart_required,
+        env_forced,
+    } = parse_detailed(
+        &with_retry(
+            retry_policy,
+            &format!("Fetching configuration from {}", host),
+            || secondary_pihole.get_config_detailed(),
+        )
+        .await
+        .with_context(|| format!("Failed to fetch configuration from {}", host))?,
+    );
+
+    let desired = desired_config(
+        &flatten(main_config),
+        &secondary_config,
+        &secondary_pihole.config,
+        ledger,
+    )?;
+    let mut changes = changed_entries(&desired, &secondary_config);
+
+    // FTL rejects the whole PATCH if it contains a key forced by an environment variable
+    changes.retain(|key, _| {
+        let forced = env_forced.contains(key);
+        if forced {
+            warn!(
+                "{}: {} is set by an environment variable on the instance and cannot be synced",
+                host, key
+            );
+        }
+        !forced
+    });
+
+    if changes.is_empty() {
+        info!("Configuration of {} is up to date", host);
+        return Ok(SyncAction::Unchanged);
+    }
+
+    for (key, value) in &changes {
+        debug!("{}: {} = {}", host, key, value);
+    }
+
+    snapshot.ensure(secondary_pihole, context).await?;
+
+    // Keys requiring a restart go into a single PATCH, so the resolver restarts only once
+    let (restart_changes, immediate_changes): (ConfigMap, ConfigMap) = changes
+        .clone()
+        .into_iter()
+        .partition(|(key, _)| restart_required.contains(key));
+
+    if !immediate_changes.is_empty() {
+        info!(
+            "Patching {} config key(s) on {}",
+            immediate_changes.len(),
+            host
+        );
+        patch(secondary_pihole, &immediate_changes, retry_policy).await?;
+    }
+
+    if !restart_changes.is_empty() {
+        info!(
+            "Patching {} config key(s) requiring a DNS restart on {}",
+            restart_changes.len(),
+            host
+        );
+        patch(secondary_pihole, &restart_changes, retry_policy).await?;
+
+        with_retry(retry_policy, &format!("Restarting DNS on {}", host), || {
+            secondary_pihole.restart_dns()
+        })
+        .await
+        .with_context(|| format!("Failed to restart DNS on {}", host))?;
+
+        info!("Waiting for {} to come back after the DNS restart", host);
+        secondary_pihole.wait_until_ready(RESTART_TIMEOUT).await?;
+    }
+
+    let applied = flatten(
+        &with_retry(
+            retry_policy,
+            &format!("Fetching configuration from {} for verification", host),
+            || secondary_pihole.get_config(),
+        )
+        .await
+        .with_context(|| format!("Failed to verify configuration on {}", host))?,
+    );
+
+    let diverged = changed_entries(&changes, &applied);
+    if !diverged.is_empty() {
+        return Err(anyhow!(
+            "Configuration verification failed on {}. Diverged keys: {}",
+            host,
+            diverged.keys().cloned().collect::<Vec<_>>().join(", ")
+        ));
+    }
+
+    info!("Configuration verified on {}", host);
+    Ok(SyncAction::Applied)
+}
+
+async fn patch(
+    secondary_pihole: &PiHoleClient,
+    changes: &ConfigMap,
+    retry_policy: &RetryConfig,
+) -> Result<()> {
+    let host = &secondary_pihole.config.host;
+    let patch = unflatten(changes);
+
+    with_retry(
+        retry_policy,
+        &format!("Patching configuration on {}", host),
+        || secondary_pihole.patch_config(&patch),
+    )
+    .await
+    .with_context(|| format!("Failed to patch configuration on {}", host))
+}
@@ -0,0 +1,168 @@
+use anyhow::{anyhow, Context, Result};
+use tracing::{debug, info};
+
+use super::{
+    config_api::sync_config_api, gravity_update, SnapshotGuard, SyncAction, SyncContext, Synced,
+};
+use crate::{
+    gravity_plan::{plan, plan_groups, prune_ownership, record_ownership, Operation},
+    pihole_client::PiHoleClient,
+    retry::with_retry,
+    state::Ledger,
+    transform,
+};
+
+/// Syncs the configuration through the Config API and gravity through the REST endpoints,
+/// as enabled by the instance's import options. Both share one snapshot taken before the
+/// first change.
+pub(super) async fn sync_rest(
+    secondary_pihole: &PiHoleClient,
+    context: &SyncContext,
+    ledger: &mut Ledger,
+) -> Result<Synced> {
+    let import_options = secondary_pihole
+        .config
+        .import_options
+        .clone()
+        .unwrap_or_default();
+
+    let mut snapshot = SnapshotGuard::default();
+    let config_action = if import_options.config {
+        sync_config_api(secondary_pihole, context, ledger, &mut snapshot).await?
+    } else {
+        SyncAction::Unchanged
+    };
+    let gravity = sync_gravity_api(secondary_pihole, context, ledger, &mut snapshot).await?;
+
+    let action = match (config_action, gravity.action) {
+        (SyncAction::Unchanged, SyncAction::Unchanged) => SyncAction::Unchanged,
+        _ => SyncAction::Applied,
+    };
+    Ok(Synced {
+        action,
+        gravity: gravity.gravity,
+    })
+}
+
+/// Applies the add, update and delete operations that make the secondary's gravity tables
+/// match main's, then verifies the result. Added and deleted entries are recorded in `ledger`.
+async fn sync_gravity_api(
+    secondary_pihole: &PiHoleClient,
+    context: &SyncContext,
+    ledger: &mut Ledger,
+    snapshot: &mut SnapshotGuard,
+) -> Result<Synced> {
+    let host = &secondary_pihole.config.host;
+    let retry_policy = &context.sync_config.retry;
+    let instance = &secondary_pihole.config;
+
+    let main_gravity = &transform::desired_gravity(
+        context
+            .main_gravity
+            .as_ref()
+            .ok_or_else(|| anyhow!("Gravity of the main instance is unavailable"))?,
+        instance,
+    )?;
+
+    let mut secondary_gravity = with_retry(
+        retry_policy,
+        &format!("Fetching gravity from {}", host),
+        || secondary_pihole.get_gravity(),
+    )
+    .await
+    .with_context(|| format!("Failed to fetch gravity from {}", host))?;
+
+    // Missing groups are created first, so entries can be assigned to their new IDs
+    let group_operations = plan_groups(main_gravity, &secondary_gravity, instance)
+        .with_context(|| format!("Failed to match groups of {}", host))?;
+    if !group_operations.is_empty() {
+        snapshot.ensure(secondary_pihole, context).await?;
+        apply_all(secondary_pihole, context, &group_operations, ledger).await?;
+
+        secondary_gravity = with_retry(
+            retry_policy,
+            &format!("Fetching gravity from {}", host),
+            || secondary_pihole.get_gravity(),
+        )
+        .await
+        .with_context(|| format!("Failed to fetch gravity from {}", host))?;
+    }
+
+    let operations = plan(main_gravity, &secondary_gravity, instance, ledger)
+        .with_context(|| format!("Failed to plan gravity changes for {}", host))?;
+    if group_operations.is_empty() && operations.is_empty() {
+        info!("Gravity of {} is up to date", host);
+        // Catches up on a gravity update that failed in an earlier run
+        return Ok(Synced {
+            action: SyncAction::Unchanged,
+            gravity: gravity_update(secondary_pihole, Some(&secondary_gravity), ledger),
+        });
+    }
+    snapshot.ensure(secondary_pihole, context).await?;
+    apply_all(secondary_pihole, context, &operations, ledger).await?;
+
+    let applied = with_retry(
+        retry_policy,
+        &format!("Fetching gravity from {} for verification", host),
+        || secondary_pihole.get_gravity(),
+    )
+    .await
+    .with_context(|| format!("Failed to verify gravity on {}", host))?;
+    prune_ownership(ledger, &applied);
+
+    let mut remaining = plan_groups(main_gravity, &applied, instance)?;
+    remaining.extend(plan(main_gravity, &applied, instance, ledger)?);
+    if !remaining.is_empty() {
+        return Err(anyhow!(
+            "Gravity verification failed on {}. Pending changes: {}",
+            host,
+            remaining
+                .iter()
+                .map(Operation::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ));
+    }
+    info!("Gravity verified on {}", host);
+
+    Ok(Synced {
+        action: SyncAction::Applied,
+        gravity: gravity_update(secondary_pihole, Some(&applied), ledger),
+    })
+}
+
+async fn apply_all(
+    secondary_pihole: &PiHoleClient,
+    context: &SyncContext,
+    operations: &[Operation],
+    ledger: &mut Ledger,
+) -> Result<()> {
+    let host = &secondary_pihole.config.host;
+    info!(
+        "Applying {} gravity change(s) on {}",
+        operations.len(),
+        host
+    );
+
+    for operation in operations {
+        debug!("{}: {}", host, operation);
+        with_retry(
+            &context.sync_config.retry,
+            &format!("{} on {}", operation, host),
+            || apply(secondary_pihole, operation),
+        )
+        .await
+        .with_context(|| format!("Failed to apply '{}' on {}", operation, host))?;
+        record_ownership(ledger, operation);
+    }
+
+    Ok(())
+}
+
+async fn apply(secondary_pihole: &PiHoleClient, operation: &Operation) -> Result<()> {
+    match operation {
+        Operation::Add(entry) => secondary_pihole.add_gravity_entry(entry).await,
+        Operation::Update(entry) => secondary_pihole.update_gravity_entry(entry).await,
+        Operation::Delete(entry) => secondary_pihole.delete_gravity_entry(entry).await,
+    }
+}
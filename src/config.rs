@@ -1,14 +1,67 @@
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
-use std::{fs, path::Path};
+use std::{collections::BTreeMap, fs, path::Path, time::Duration};
 
-#[derive(Debug, Serialize, Deserialize)]
+use crate::{
+    pihole_config::{path_matches, ConfigMap},
+    schedule::{optional_duration, Schedule},
+};
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct SyncConfig {
-    pub interval: u64,
+    pub interval: Schedule,
+    /// Run a sync immediately on startup instead of waiting for the first scheduled run
+    #[serde(default = "default_true")]
+    pub run_on_start: bool,
+    /// Maximum random delay added to every scheduled run. Runs due at the same time get the
+    /// same delay.
+    #[serde(
+        default,
+        with = "optional_duration",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub jitter: Option<Duration>,
     pub cache_location: String,
+    #[serde(default = "default_max_parallel")]
+    pub max_parallel: usize,
+    /// Deadline in seconds for syncing a single secondary, not including its gravity update
+    #[serde(default = "default_secondary_timeout")]
+    pub secondary_timeout: u64,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    /// Number of pre-sync snapshots kept per secondary
+    #[serde(default = "default_snapshot_retention")]
+    pub snapshot_retention: usize,
+    #[serde(default)]
+    pub backup_retention: BackupRetention,
 }
 
+/// Retention policy for the archive history of the main instance
 #[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BackupRetention {
+    #[serde(default = "default_keep_last")]
+    pub keep_last: usize,
+    #[serde(default = "default_keep_daily")]
+    pub keep_daily: usize,
+    #[serde(default = "default_keep_weekly")]
+    pub keep_weekly: usize,
+}
+
+/// Retry policy for requests against Pi-hole instances. Delays are given in seconds.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RetryConfig {
+    #[serde(default = "default_retry_max_attempts")]
+    pub max_attempts: u32,
+    #[serde(default = "default_retry_base_delay")]
+    pub base_delay: u64,
+    #[serde(default = "default_retry_max_delay")]
+    pub max_delay: u64,
+    /// Random extra delay as a fraction of the computed delay (0.0 - 1.0)
+    #[serde(default = "default_retry_jitter")]
+    pub jitter: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct InstanceConfig {
     pub host: String,
     pub schema: String,
@@ -16,6 +69,139 @@ pub struct InstanceConfig {
     pub api_key: String,
     pub update_gravity: Option<bool>,
     pub import_options: Option<SyncImportOptions>,
+    /// Disabled instances are kept in the config but never synced
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub enabled: Option<bool>,
+    /// Overrides `sync.interval` for this instance
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interval: Option<Schedule>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<SyncMode>,
+    /// Limits which config keys are synced to this instance
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config_filter: Option<ConfigFilter>,
+    /// Config values set on this instance instead of main's, keyed by dotted path.
+    /// String values may reference `{{host}}` and the instance's `vars`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub overrides: Option<ConfigMap>,
+    /// Custom variables available to `overrides`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vars: Option<BTreeMap<String, String>>,
+    /// Merge instead of replace local DNS records and CNAMEs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dns_records: Option<DnsRecordsConfig>,
+    /// Changes the built-in list of config keys that are never synced to this instance
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protected_keys: Option<ProtectedKeys>,
+    /// Whether gravity entries that main does not have are removed (REST mode)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gravity_strategy: Option<GravityStrategy>,
+    /// Limits which adlists, domains and clients are synced, by tags in their comment
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tag_filter: Option<TagFilter>,
+    /// Names of the only groups synced to this instance, with their adlists, domains and clients
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub include_groups: Option<Vec<String>>,
+    /// Names of groups that are not synced to this instance
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exclude_groups: Option<Vec<String>>,
+    /// Rewrites main's client identifiers to this instance's network, first matching rule wins
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_rewrites: Option<Vec<ClientRewrite>>,
+}
+
+/// Glob patterns on dotted config paths, added to or removed from the built-in protected keys
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ProtectedKeys {
+    /// Additional keys that keep the secondary's own value
+    #[serde(default)]
+    pub add: Vec<String>,
+    /// Protected keys that are synced from main anyway
+    #[serde(default)]
+    pub allow: Vec<String>,
+}
+
+/// How `dns.hosts` and `dns.cnameRecords` from main are applied to a secondary
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct DnsRecordsConfig {
+    #[serde(default)]
+    pub strategy: RecordStrategy,
+    /// Applies when main and the secondary define different records for the same name
+    #[serde(default)]
+    pub conflict: ConflictRule,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum GravityStrategy {
+    /// The secondary's groups, adlists, domains and clients are made identical to main's
+    #[default]
+    Mirror,
+    /// Main's entries are added and updated, but only entries the sync created are removed
+    Merge,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordStrategy {
+    /// The secondary's records are replaced by main's
+    #[default]
+    Replace,
+    /// Main's records are added to the secondary's own records
+    Merge,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictRule {
+    #[default]
+    MainWins,
+    SecondaryWins,
+    Fail,
+}
+
+/// Glob patterns on dotted config paths (e.g. `dns.*`). Keys matching an exclude
+/// pattern keep the secondary's own value. An empty include list includes everything.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ConfigFilter {
+    #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+
+/// Tags (whitespace separated words such as `#site-a`) in the comment of adlists, domains
+/// and clients. Entries with an excluded tag are not synced. An empty include list includes
+/// every entry, otherwise entries need at least one included tag.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct TagFilter {
+    #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+
+/// Maps a client of main to a client of a secondary. If `from` is a network (`10.1.0.0/16`),
+/// addresses and subnets inside it are moved to the network `to` of the same size. Any
+/// other identifier (MAC address, hostname, interface) is replaced if it matches exactly.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ClientRewrite {
+    pub from: String,
+    pub to: String,
+}
+
+/// How a secondary instance is synced
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncMode {
+    /// Upload the main instance's Teleporter archive
+    #[default]
+    Teleporter,
+    /// Patch changed settings through the Config API, gravity is not synced
+    ConfigApi,
+    /// Patch changed settings through the Config API and apply gravity changes through
+    /// the REST endpoints for groups, lists, domains and clients
+    Rest,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -46,9 +232,34 @@ pub struct GravitySyncIncludes {
     pub client_by_group: bool,
 }
 
+/// Limits on gravity updates of secondaries, which all download the same blocklists
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct GravityConfig {
+    /// Maximum number of secondaries running gravity at the same time (default: no limit)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_concurrent: Option<usize>,
+    /// Minimum time between the starts of two gravity updates
+    #[serde(
+        default,
+        with = "optional_duration",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub delay: Option<Duration>,
+    /// Maximum duration of one gravity run (default: 30 minutes). Gravity runs after the sync
+    /// of a secondary and is not part of its `secondary_timeout`.
+    #[serde(
+        default,
+        with = "optional_duration",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub timeout: Option<Duration>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
     pub sync: SyncConfig,
+    #[serde(default)]
+    pub gravity: GravityConfig,
     pub main: InstanceConfig,
     pub secondary: Vec<InstanceConfig>,
 }
@@ -57,6 +268,46 @@ fn default_true() -> bool {
     true
 }
 
+fn default_max_parallel() -> usize {
+    4
+}
+
+fn default_secondary_timeout() -> u64 {
+    300
+}
+
+fn default_snapshot_retention() -> usize {
+    10
+}
+
+fn default_keep_last() -> usize {
+    10
+}
+
+fn default_keep_daily() -> usize {
+    7
+}
+
+fn default_keep_weekly() -> usize {
+    4
+}
+
+fn default_retry_max_attempts() -> u32 {
+    3
+}
+
+fn default_retry_base_delay() -> u64 {
+    2
+}
+
+fn default_retry_max_delay() -> u64 {
+    60
+}
+
+fn default_retry_jitter() -> f64 {
+    0.2
+}
+
 impl Default for SyncImportOptions {
     fn default() -> Self {
         Self {
@@ -67,6 +318,27 @@ impl Default for SyncImportOptions {
     }
 }
 
+impl Default for BackupRetention {
+    fn default() -> Self {
+        Self {
+            keep_last: default_keep_last(),
+            keep_daily: default_keep_daily(),
+            keep_weekly: default_keep_weekly(),
+        }
+    }
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: default_retry_max_attempts(),
+            base_delay: default_retry_base_delay(),
+            max_delay: default_retry_max_delay(),
+            jitter: default_retry_jitter(),
+        }
+    }
+}
+
 impl Default for GravitySyncIncludes {
     fn default() -> Self {
         Self {
@@ -81,6 +353,74 @@ impl Default for GravitySyncIncludes {
     }
 }
 
+impl GravitySyncIncludes {
+    /// Names of the gravity database tables that are imported with these settings
+    pub fn enabled_tables(&self) -> Vec<&'static str> {
+        [
+            ("group", self.group),
+            ("adlist", self.adlist),
+            ("adlist_by_group", self.adlist_by_group),
+            ("domainlist", self.domainlist),
+            ("domainlist_by_group", self.domainlist_by_group),
+            ("client", self.client),
+            ("client_by_group", self.client_by_group),
+        ]
+        .into_iter()
+        .filter(|(_, enabled)| *enabled)
+        .map(|(table, _)| table)
+        .collect()
+    }
+}
+
+impl InstanceConfig {
+    /// Whether gravity sync is limited to some of main's groups
+    pub fn scopes_groups(&self) -> bool {
+        self.include_groups.is_some() || self.exclude_groups.is_some()
+    }
+
+    /// Whether the group with the given name is synced to this instance
+    pub fn syncs_group(&self, name: &str) -> bool {
+        let included = self
+            .include_groups
+            .as_ref()
+            .is_none_or(|groups| groups.iter().any(|group| group == name));
+        included
+            && !self
+                .exclude_groups
+                .as_ref()
+                .is_some_and(|groups| groups.iter().any(|group| group == name))
+    }
+}
+
+impl ConfigFilter {
+    /// Whether the value of `path` is taken from the main instance
+    pub fn allows(&self, path: &str) -> bool {
+        let included = self.include.is_empty()
+            || self
+                .include
+                .iter()
+                .any(|pattern| path_matches(pattern, path));
+        included
+            && !self
+                .exclude
+                .iter()
+                .any(|pattern| path_matches(pattern, path))
+    }
+}
+
+impl TagFilter {
+    /// Whether an entry with the given comment is synced
+    pub fn allows(&self, comment: Option<&str>) -> bool {
+        let tags = comment
+            .unwrap_or_default()
+            .split_whitespace()
+            .collect::<Vec<_>>();
+        let included =
+            self.include.is_empty() || self.include.iter().any(|tag| tags.contains(&tag.as_str()));
+        included && !self.exclude.iter().any(|tag| tags.contains(&tag.as_str()))
+    }
+}
+
 impl Config {
     pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
         let content = fs::read_to_string(&path)
@@ -107,9 +447,46 @@ impl Config {
             }
         };
 
+        config.validate()?;
         Ok(config)
     }
 
+    /// Rejects instance settings that cannot apply to the instance's sync mode
+    fn validate(&self) -> Result<()> {
+        for instance in &self.secondary {
+            match instance.mode.unwrap_or_default() {
+                // An archive import replaces the gravity tables, it cannot keep local entries
+                SyncMode::Teleporter
+                    if instance.gravity_strategy == Some(GravityStrategy::Merge) =>
+                {
+                    return Err(anyhow::anyhow!(
+                        "gravity_strategy 'merge' of {} requires mode 'rest'",
+                        instance.host
+                    ));
+                }
+                SyncMode::ConfigApi => {
+                    let gravity_settings = [
+                        ("gravity_strategy", instance.gravity_strategy.is_some()),
+                        ("tag_filter", instance.tag_filter.is_some()),
+                        ("include_groups", instance.include_groups.is_some()),
+                        ("exclude_groups", instance.exclude_groups.is_some()),
+                        ("client_rewrites", instance.client_rewrites.is_some()),
+                    ];
+                    if let Some((name, _)) = gravity_settings.iter().find(|(_, set)| *set) {
+                        return Err(anyhow::anyhow!(
+                            "{} of {} has no effect in mode 'config_api', which does not sync gravity",
+                            name,
+                            instance.host
+                        ));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
         // Get file extension
         let extension = path
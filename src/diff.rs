@@ -0,0 +1,276 @@
+use anyhow::Result;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::{collections::BTreeMap, fmt};
+
+use crate::{
+    config::SyncImportOptions, gravity::Gravity, pihole_config::flatten,
+    teleporter::TeleporterArchive,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Section {
+    Config,
+    Groups,
+    Adlists,
+    Domains,
+    Clients,
+}
+
+impl fmt::Display for Section {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Section::Config => "config",
+            Section::Groups => "groups",
+            Section::Adlists => "adlists",
+            Section::Domains => "domains",
+            Section::Clients => "clients",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Comparable view of an instance's configuration. Every section maps a stable key
+/// (config path, group name, list address, ...) to the entry's relevant fields.
+#[derive(Debug, Default)]
+pub struct Snapshot {
+    sections: BTreeMap<Section, BTreeMap<String, Value>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Change {
+    pub from: Value,
+    pub to: Value,
+}
+
+/// Differences of one section, from the secondary's point of view:
+/// `added` entries exist only on main, `removed` entries exist only on the secondary.
+#[derive(Debug, Serialize)]
+pub struct SectionDiff {
+    pub section: Section,
+    pub added: BTreeMap<String, Value>,
+    pub removed: BTreeMap<String, Value>,
+    pub changed: BTreeMap<String, Change>,
+}
+
+impl Snapshot {
+    /// Builds a snapshot of the sections a secondary with the given import options would apply
+    pub fn from_archive(archive: &TeleporterArchive, options: &SyncImportOptions) -> Result<Self> {
+        let mut snapshot = Snapshot::default();
+
+        if options.config {
+            let entries = match archive.pihole_toml()? {
+                Some(table) => flatten(&serde_json::to_value(table)?),
+                None => BTreeMap::new(),
+            };
+            snapshot.sections.insert(Section::Config, entries);
+        }
+
+        let gravity = archive.gravity()?.unwrap_or_default();
+        let includes = &options.gravity;
+
+        if includes.group {
+            snapshot
+                .sections
+                .insert(Section::Groups, group_entries(&gravity));
+        }
+        if includes.adlist {
+            snapshot.sections.insert(
+                Section::Adlists,
+                adlist_entries(&gravity, includes.adlist_by_group),
+            );
+        }
+        if includes.domainlist {
+            snapshot.sections.insert(
+                Section::Domains,
+                domain_entries(&gravity, includes.domainlist_by_group),
+            );
+        }
+        if includes.client {
+            snapshot.sections.insert(
+                Section::Clients,
+                client_entries(&gravity, includes.client_by_group),
+            );
+        }
+
+        Ok(snapshot)
+    }
+
+    /// Whether a section contains an entry with the given key
+    pub fn contains(&self, section: Section, key: &str) -> bool {
+        self.sections
+            .get(&section)
+            .is_some_and(|entries| entries.contains_key(key))
+    }
+
+    /// Keeps only the entries of a section whose key satisfies `keep`
+    pub fn retain(&mut self, section: Section, keep: impl Fn(&str) -> bool) {
+        if let Some(entries) = self.sections.get_mut(&section) {
+            entries.retain(|key, _| keep(key));
+        }
+    }
+
+    /// Number of entries per section
+    pub fn entry_counts(&self) -> impl Iterator<Item = (Section, usize)> + '_ {
+        self.sections
+            .iter()
+            .map(|(section, entries)| (*section, entries.len()))
+    }
+}
+
+/// Compares all sections of the main snapshot against the secondary's snapshot
+pub fn diff(main: &Snapshot, secondary: &Snapshot) -> Vec<SectionDiff> {
+    let empty = BTreeMap::new();
+
+    main.sections
+        .iter()
+        .map(|(section, main_entries)| {
+            let secondary_entries = secondary.sections.get(section).unwrap_or(&empty);
+            diff_entries(*section, main_entries, secondary_entries)
+        })
+        .collect()
+}
+
+fn diff_entries(
+    section: Section,
+    main: &BTreeMap<String, Value>,
+    secondary: &BTreeMap<String, Value>,
+) -> SectionDiff {
+    let mut diff = SectionDiff {
+        section,
+        added: BTreeMap::new(),
+        removed: BTreeMap::new(),
+        changed: BTreeMap::new(),
+    };
+
+    for (key, main_value) in main {
+        match secondary.get(key) {
+            None => {
+                diff.added.insert(key.clone(), main_value.clone());
+            }
+            Some(secondary_value) if secondary_value != main_value => {
+                diff.changed.insert(
+                    key.clone(),
+                    Change {
+                        from: secondary_value.clone(),
+                        to: main_value.clone(),
+                    },
+                );
+            }
+            Some(_) => {}
+        }
+    }
+
+    for (key, secondary_value) in secondary {
+        if !main.contains_key(key) {
+            diff.removed.insert(key.clone(), secondary_value.clone());
+        }
+    }
+
+    diff
+}
+
+impl SectionDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+impl fmt::Display for SectionDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return write!(f, "[{}] no differences", self.section);
+        }
+
+        write!(
+            f,
+            "[{}] {} changed, {} added, {} removed",
+            self.section,
+            self.changed.len(),
+            self.added.len(),
+            self.removed.len()
+        )?;
+
+        for (key, change) in &self.changed {
+            write!(f, "\n  ~ {}: {} -> {}", key, change.from, change.to)?;
+        }
+        for (key, value) in &self.added {
+            write!(f, "\n  + {}: {}", key, value)?;
+        }
+        for (key, value) in &self.removed {
+            write!(f, "\n  - {}: {}", key, value)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn group_names(gravity: &Gravity, ids: &[i64]) -> Value {
+    let mut names = ids
+        .iter()
+        .map(|id| {
+            gravity
+                .group_name(*id)
+                .map(str::to_string)
+                .unwrap_or_else(|| format!("#{}", id))
+        })
+        .collect::<Vec<_>>();
+    names.sort();
+    json!(names)
+}
+
+fn group_entries(gravity: &Gravity) -> BTreeMap<String, Value> {
+    gravity
+        .groups
+        .iter()
+        .map(|group| {
+            (
+                group.name.clone(),
+                json!({ "enabled": group.enabled, "comment": group.comment }),
+            )
+        })
+        .collect()
+}
+
+fn adlist_entries(gravity: &Gravity, with_groups: bool) -> BTreeMap<String, Value> {
+    gravity
+        .adlists
+        .iter()
+        .map(|adlist| {
+            let mut value = json!({ "enabled": adlist.enabled, "comment": adlist.comment });
+            if with_groups {
+                value["groups"] = group_names(gravity, &adlist.groups);
+            }
+            (adlist.key(), value)
+        })
+        .collect()
+}
+
+fn domain_entries(gravity: &Gravity, with_groups: bool) -> BTreeMap<String, Value> {
+    gravity
+        .domains
+        .iter()
+        .map(|domain| {
+            let mut value = json!({ "enabled": domain.enabled, "comment": domain.comment });
+            if with_groups {
+                value["groups"] = group_names(gravity, &domain.groups);
+            }
+            (domain.key(), value)
+        })
+        .collect()
+}
+
+fn client_entries(gravity: &Gravity, with_groups: bool) -> BTreeMap<String, Value> {
+    gravity
+        .clients
+        .iter()
+        .map(|client| {
+            let mut value = json!({ "comment": client.comment });
+            if with_groups {
+                value["groups"] = group_names(gravity, &client.groups);
+            }
+            (client.client.clone(), value)
+        })
+        .collect()
+}
@@ -0,0 +1,263 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// ID of the group every Pi-hole has and that cannot be deleted
+pub const DEFAULT_GROUP_ID: i64 = 0;
+
+/// Gravity database contents in the shape used by the Pi-hole REST API.
+/// Group assignments reference group IDs of the instance the data was read from.
+#[derive(Debug, Default, Clone)]
+pub struct Gravity {
+    pub groups: Vec<Group>,
+    pub adlists: Vec<Adlist>,
+    pub domains: Vec<Domain>,
+    pub clients: Vec<Client>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Group {
+    pub id: i64,
+    pub name: String,
+    pub enabled: bool,
+    pub comment: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ListType {
+    Block,
+    Allow,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Adlist {
+    pub id: i64,
+    pub address: String,
+    #[serde(rename = "type")]
+    pub list_type: ListType,
+    pub enabled: bool,
+    pub comment: Option<String>,
+    pub groups: Vec<i64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DomainType {
+    Allow,
+    Deny,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DomainKind {
+    Exact,
+    Regex,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Domain {
+    pub id: i64,
+    pub domain: String,
+    #[serde(rename = "type")]
+    pub domain_type: DomainType,
+    pub kind: DomainKind,
+    pub enabled: bool,
+    pub comment: Option<String>,
+    pub groups: Vec<i64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Client {
+    pub id: i64,
+    pub client: String,
+    pub comment: Option<String>,
+    pub groups: Vec<i64>,
+}
+
+/// A single row of any gravity table
+#[derive(Debug, Clone)]
+pub enum GravityEntry {
+    Group(Group),
+    Adlist(Adlist),
+    Domain(Domain),
+    Client(Client),
+}
+
+impl ListType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ListType::Block => "block",
+            ListType::Allow => "allow",
+        }
+    }
+}
+
+impl DomainType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DomainType::Allow => "allow",
+            DomainType::Deny => "deny",
+        }
+    }
+}
+
+impl DomainKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DomainKind::Exact => "exact",
+            DomainKind::Regex => "regex",
+        }
+    }
+}
+
+impl Domain {
+    /// Maps the combined `type` column of gravity.db's domainlist table
+    pub fn type_and_kind_from_db(value: i64) -> Option<(DomainType, DomainKind)> {
+        match value {
+            0 => Some((DomainType::Allow, DomainKind::Exact)),
+            1 => Some((DomainType::Deny, DomainKind::Exact)),
+            2 => Some((DomainType::Allow, DomainKind::Regex)),
+            3 => Some((DomainType::Deny, DomainKind::Regex)),
+            _ => None,
+        }
+    }
+}
+
+impl Gravity {
+    /// All rows of the group, adlist, domain and client tables
+    pub fn entries(&self) -> impl Iterator<Item = GravityEntry> + '_ {
+        self.groups
+            .iter()
+            .cloned()
+            .map(GravityEntry::Group)
+            .chain(self.adlists.iter().cloned().map(GravityEntry::Adlist))
+            .chain(self.domains.iter().cloned().map(GravityEntry::Domain))
+            .chain(self.clients.iter().cloned().map(GravityEntry::Client))
+    }
+
+    /// Fingerprint of the adlists gravity downloads: their addresses, types and enabled states
+    pub fn adlists_hash(&self) -> String {
+        let adlists = self
+            .adlists
+            .iter()
+            .map(|adlist| (adlist.key(), adlist.enabled))
+            .collect::<BTreeSet<_>>();
+
+        let mut hasher = Sha256::new();
+        for (key, enabled) in adlists {
+            hasher.update((key.len() as u64).to_le_bytes());
+            hasher.update(key.as_bytes());
+            hasher.update([enabled as u8]);
+        }
+        format!("{:x}", hasher.finalize())
+    }
+
+    pub fn group_name(&self, id: i64) -> Option<&str> {
+        self.groups
+            .iter()
+            .find(|group| group.id == id)
+            .map(|group| group.name.as_str())
+    }
+}
+
+impl GravityEntry {
+    /// Name of the table the entry belongs to
+    pub fn kind(&self) -> &'static str {
+        match self {
+            GravityEntry::Group(_) => "group",
+            GravityEntry::Adlist(_) => "adlist",
+            GravityEntry::Domain(_) => "domain",
+            GravityEntry::Client(_) => "client",
+        }
+    }
+
+    /// Identifies the entry across instances, independent of its database ID
+    pub fn key(&self) -> String {
+        match self {
+            GravityEntry::Group(group) => group.name.clone(),
+            GravityEntry::Adlist(adlist) => adlist.key(),
+            GravityEntry::Domain(domain) => domain.key(),
+            GravityEntry::Client(client) => client.client.clone(),
+        }
+    }
+}
+
+impl Adlist {
+    pub fn key(&self) -> String {
+        format!("{}/{}", self.list_type.as_str(), self.address)
+    }
+}
+
+impl Domain {
+    pub fn key(&self) -> String {
+        format!(
+            "{}/{}/{}",
+            self.domain_type.as_str(),
+            self.kind.as_str(),
+            self.domain
+        )
+    }
+}
+
+/// Maps the group IDs of one instance to the IDs of the same-named groups on another
+#[derive(Debug, Default)]
+pub struct GroupMap {
+    ids: BTreeMap<i64, i64>,
+    /// Source groups without a unique counterpart, with the reason
+    problems: BTreeMap<i64, String>,
+}
+
+impl GroupMap {
+    /// Matches groups by exact name. A group whose name only differs in case or surrounding
+    /// whitespace is a collision and never mapped, to avoid assigning entries to the wrong group.
+    pub fn by_name(from: &[Group], to: &[Group]) -> Self {
+        let mut map = GroupMap::default();
+
+        for group in from {
+            if let Some(target) = to.iter().find(|target| target.name == group.name) {
+                map.ids.insert(group.id, target.id);
+                continue;
+            }
+
+            let similar = to
+                .iter()
+                .filter(|target| target.name.trim().eq_ignore_ascii_case(group.name.trim()))
+                .map(|target| format!("'{}'", target.name))
+                .collect::<Vec<_>>();
+            let problem = if similar.is_empty() {
+                format!("group '{}' does not exist", group.name)
+            } else {
+                format!(
+                    "group '{}' collides with {}",
+                    group.name,
+                    similar.join(", ")
+                )
+            };
+            map.problems.insert(group.id, problem);
+        }
+
+        map
+    }
+
+    /// Name collisions between the two instances
+    pub fn collisions(&self) -> Vec<&str> {
+        self.problems
+            .values()
+            .filter(|problem| problem.contains("collides"))
+            .map(String::as_str)
+            .collect()
+    }
+
+    /// Translates group IDs, failing for groups without a unique counterpart
+    pub fn translate(&self, ids: &[i64]) -> Result<Vec<i64>> {
+        ids.iter()
+            .map(|id| match (self.ids.get(id), self.problems.get(id)) {
+                (Some(target), _) => Ok(*target),
+                (None, Some(problem)) => Err(anyhow!("{}", problem)),
+                (None, None) => Err(anyhow!("unknown group ID {}", id)),
+            })
+            .collect()
+    }
+}
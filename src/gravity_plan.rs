@@ -0,0 +1,641 @@
+use anyhow::{anyhow, Context, Result};
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    fmt,
+};
+
+use crate::{
+    config::{GravityStrategy, InstanceConfig},
+    gravity::{Adlist, Client, Domain, Gravity, GravityEntry, GroupMap, DEFAULT_GROUP_ID},
+    state::Ledger,
+};
+
+static NOTHING_OWNED: BTreeSet<String> = BTreeSet::new();
+
+/// A change to a secondary's gravity database through the REST API
+#[derive(Debug, Clone)]
+pub enum Operation {
+    Add(GravityEntry),
+    /// Replaces the fields of the entry with the same key
+    Update(GravityEntry),
+    Delete(GravityEntry),
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (sign, entry) = match self {
+            Operation::Add(entry) => ('+', entry),
+            Operation::Update(entry) => ('~', entry),
+            Operation::Delete(entry) => ('-', entry),
+        };
+        write!(f, "{} {} {}", sign, entry.kind(), entry.key())
+    }
+}
+
+/// Rows of the adlist, domainlist and client tables, which all carry group assignments
+trait GroupedEntry: Clone {
+    fn key(&self) -> String;
+    fn groups(&self) -> &[i64];
+    fn set_groups(&mut self, groups: Vec<i64>);
+    /// Compares all synced fields except the key and group assignments
+    fn same_fields(&self, other: &Self) -> bool;
+    fn into_entry(self) -> GravityEntry;
+}
+
+impl GroupedEntry for Adlist {
+    fn key(&self) -> String {
+        Adlist::key(self)
+    }
+    fn groups(&self) -> &[i64] {
+        &self.groups
+    }
+    fn set_groups(&mut self, groups: Vec<i64>) {
+        self.groups = groups;
+    }
+    fn same_fields(&self, other: &Self) -> bool {
+        self.enabled == other.enabled && self.comment == other.comment
+    }
+    fn into_entry(self) -> GravityEntry {
+        GravityEntry::Adlist(self)
+    }
+}
+
+impl GroupedEntry for Domain {
+    fn key(&self) -> String {
+        Domain::key(self)
+    }
+    fn groups(&self) -> &[i64] {
+        &self.groups
+    }
+    fn set_groups(&mut self, groups: Vec<i64>) {
+        self.groups = groups;
+    }
+    fn same_fields(&self, other: &Self) -> bool {
+        self.enabled == other.enabled && self.comment == other.comment
+    }
+    fn into_entry(self) -> GravityEntry {
+        GravityEntry::Domain(self)
+    }
+}
+
+impl GroupedEntry for Client {
+    fn key(&self) -> String {
+        self.client.clone()
+    }
+    fn groups(&self) -> &[i64] {
+        &self.groups
+    }
+    fn set_groups(&mut self, groups: Vec<i64>) {
+        self.groups = groups;
+    }
+    fn same_fields(&self, other: &Self) -> bool {
+        self.comment == other.comment
+    }
+    fn into_entry(self) -> GravityEntry {
+        GravityEntry::Client(self)
+    }
+}
+
+/// Group additions and updates that make the secondary's groups match main's by name.
+/// They have to be applied before [`plan`], which needs every group to exist. Without the
+/// group table, group assignments are still mapped by name, so missing groups are created
+/// but existing ones are left as they are.
+pub fn plan_groups(
+    main: &Gravity,
+    secondary: &Gravity,
+    instance: &InstanceConfig,
+) -> Result<Vec<Operation>> {
+    let includes = instance.import_options.clone().unwrap_or_default().gravity;
+    let assigns_groups = (includes.adlist && includes.adlist_by_group)
+        || (includes.domainlist && includes.domainlist_by_group)
+        || (includes.client && includes.client_by_group);
+    if !includes.group && !assigns_groups {
+        return Ok(Vec::new());
+    }
+
+    let groups = GroupMap::by_name(&main.groups, &secondary.groups);
+    let collisions = groups.collisions();
+    if !collisions.is_empty() {
+        return Err(anyhow!("Ambiguous group names: {}", collisions.join(", ")));
+    }
+
+    let own_groups = secondary
+        .groups
+        .iter()
+        .map(|own| (own.name.as_str(), own))
+        .collect::<HashMap<_, _>>();
+
+    let mut operations = Vec::new();
+    for group in &main.groups {
+        match own_groups.get(group.name.as_str()) {
+            None => operations.push(Operation::Add(GravityEntry::Group(group.clone()))),
+            Some(own)
+                if includes.group
+                    && (own.enabled != group.enabled || own.comment != group.comment) =>
+            {
+                operations.push(Operation::Update(GravityEntry::Group(group.clone())))
+            }
+            Some(_) => {}
+        }
+    }
+
+    Ok(operations)
+}
+
+/// Operations that make the secondary's adlist, domain and client tables match main's for
+/// the included tables, followed by the deletion of groups main does not have. Group
+/// assignments are translated to the secondary's groups of the same name; a group without
+/// a unique counterpart is an error rather than a guess. With the merge strategy, only
+/// entries the ledger records as added by the sync are deleted. If the instance only syncs
+/// some groups, the secondary's other groups, their entries and assignments are left alone.
+pub fn plan(
+    main: &Gravity,
+    secondary: &Gravity,
+    instance: &InstanceConfig,
+    ledger: &Ledger,
+) -> Result<Vec<Operation>> {
+    let includes = instance.import_options.clone().unwrap_or_default().gravity;
+    let scoped_ids = secondary
+        .groups
+        .iter()
+        .filter(|group| instance.syncs_group(&group.name))
+        .map(|group| group.id)
+        .collect::<HashSet<_>>();
+    let in_scope = |id: i64| scoped_ids.contains(&id);
+    let scope: Option<&dyn Fn(i64) -> bool> = instance.scopes_groups().then_some(&in_scope);
+    let deletable = |kind: &str| match instance.gravity_strategy.unwrap_or_default() {
+        GravityStrategy::Mirror => None,
+        GravityStrategy::Merge => Some(ledger.gravity.get(kind).unwrap_or(&NOTHING_OWNED)),
+    };
+    let groups = GroupMap::by_name(&main.groups, &secondary.groups);
+    let mut operations = Vec::new();
+    let mut deletions = Vec::new();
+
+    if includes.adlist {
+        reconcile(
+            &translate(&main.adlists, &groups, includes.adlist_by_group)?,
+            &secondary.adlists,
+            includes.adlist_by_group,
+            deletable("adlist"),
+            scope,
+            &mut operations,
+            &mut deletions,
+        );
+    }
+    if includes.domainlist {
+        reconcile(
+            &translate(&main.domains, &groups, includes.domainlist_by_group)?,
+            &secondary.domains,
+            includes.domainlist_by_group,
+            deletable("domain"),
+            scope,
+            &mut operations,
+            &mut deletions,
+        );
+    }
+    if includes.client {
+        reconcile(
+            &translate(&main.clients, &groups, includes.client_by_group)?,
+            &secondary.clients,
+            includes.client_by_group,
+            deletable("client"),
+            scope,
+            &mut operations,
+            &mut deletions,
+        );
+    }
+    operations.extend(deletions);
+
+    if includes.group {
+        let main_groups = main
+            .groups
+            .iter()
+            .map(|group| group.name.as_str())
+            .collect::<HashSet<_>>();
+        for own in &secondary.groups {
+            if own.id != DEFAULT_GROUP_ID
+                && !main_groups.contains(own.name.as_str())
+                && instance.syncs_group(&own.name)
+                && deletable("group").is_none_or(|owned| owned.contains(&own.name))
+            {
+                operations.push(Operation::Delete(GravityEntry::Group(own.clone())));
+            }
+        }
+    }
+
+    Ok(operations)
+}
+
+/// Main's entries with their group assignments in the secondary's group IDs
+fn translate<T: GroupedEntry>(
+    entries: &[T],
+    groups: &GroupMap,
+    with_groups: bool,
+) -> Result<Vec<T>> {
+    entries
+        .iter()
+        .map(|entry| {
+            let mut entry = entry.clone();
+            if with_groups {
+                let translated = groups
+                    .translate(entry.groups())
+                    .with_context(|| format!("Cannot assign groups of {}", entry.key()))?;
+                entry.set_groups(translated);
+            }
+            Ok(entry)
+        })
+        .collect()
+}
+
+/// Adds and updates entries of one table. Deletions are collected separately, so they run
+/// after all additions. Only keys in `deletable` are deleted, if given. If a `scope` of
+/// synced group IDs is given, entries outside of it are not deleted and their assignments
+/// to other groups are kept.
+fn reconcile<T: GroupedEntry>(
+    main: &[T],
+    secondary: &[T],
+    with_groups: bool,
+    deletable: Option<&BTreeSet<String>>,
+    scope: Option<&dyn Fn(i64) -> bool>,
+    operations: &mut Vec<Operation>,
+    deletions: &mut Vec<Operation>,
+) {
+    let own_by_key = secondary
+        .iter()
+        .map(|own| (own.key(), own))
+        .collect::<HashMap<_, _>>();
+    let main_keys = main.iter().map(T::key).collect::<HashSet<_>>();
+
+    for entry in main {
+        let own = own_by_key.get(&entry.key()).copied();
+
+        let mut wanted = entry.clone();
+        if !with_groups {
+            // Assignments are not synced: keep the secondary's, new entries go to the default group
+            wanted.set_groups(
+                own.map(|own| own.groups().to_vec())
+                    .unwrap_or_else(|| vec![DEFAULT_GROUP_ID]),
+            );
+        } else if let (Some(own), Some(scope)) = (own, scope) {
+            let mut groups = wanted.groups().to_vec();
+            groups.extend(own.groups().iter().filter(|id| !scope(**id)));
+            wanted.set_groups(groups);
+        }
+
+        match own {
+            None => operations.push(Operation::Add(wanted.into_entry())),
+            Some(own) if !own.same_fields(&wanted) || !same_groups(own, &wanted) => {
+                operations.push(Operation::Update(wanted.into_entry()))
+            }
+            Some(_) => {}
+        }
+    }
+
+    for own in secondary {
+        let key = own.key();
+        if !main_keys.contains(&key)
+            && deletable.is_none_or(|owned| owned.contains(&key))
+            && scope.is_none_or(|scope| own.groups().iter().any(|id| scope(*id)))
+        {
+            deletions.push(Operation::Delete(own.clone().into_entry()));
+        }
+    }
+}
+
+/// Records entries the sync adds to a secondary in the ledger and forgets deleted ones
+pub fn record_ownership(ledger: &mut Ledger, operation: &Operation) {
+    match operation {
+        Operation::Add(entry) => {
+            ledger
+                .gravity
+                .entry(entry.kind().to_string())
+                .or_default()
+                .insert(entry.key());
+        }
+        Operation::Delete(entry) => {
+            if let Some(owned) = ledger.gravity.get_mut(entry.kind()) {
+                owned.remove(&entry.key());
+            }
+        }
+        Operation::Update(_) => {}
+    }
+}
+
+/// Forgets added entries that were removed from the secondary by other means, so a local
+/// entry created later under the same key is never deleted
+pub fn prune_ownership(ledger: &mut Ledger, secondary: &Gravity) {
+    let mut existing = HashMap::<&str, HashSet<String>>::new();
+    for entry in secondary.entries() {
+        existing
+            .entry(entry.kind())
+            .or_default()
+            .insert(entry.key());
+    }
+
+    for (kind, owned) in ledger.gravity.iter_mut() {
+        let keys = existing.get(kind.as_str());
+        owned.retain(|key| keys.is_some_and(|keys| keys.contains(key)));
+    }
+}
+
+fn same_groups<T: GroupedEntry>(a: &T, b: &T) -> bool {
+    let mut a = a.groups().to_vec();
+    let mut b = b.groups().to_vec();
+    a.sort_unstable();
+    b.sort_unstable();
+    a == b
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{GravitySyncIncludes, SyncImportOptions},
+        gravity::{Group, ListType},
+    };
+
+    fn group(id: i64, name: &str) -> Group {
+        Group {
+            id,
+            name: name.to_string(),
+            enabled: true,
+            comment: None,
+        }
+    }
+
+    fn adlist(address: &str, groups: &[i64]) -> Adlist {
+        Adlist {
+            id: 0,
+            address: address.to_string(),
+            list_type: ListType::Block,
+            enabled: true,
+            comment: None,
+            groups: groups.to_vec(),
+        }
+    }
+
+    fn gravity(groups: &[(i64, &str)], adlists: Vec<Adlist>) -> Gravity {
+        Gravity {
+            groups: groups.iter().map(|(id, name)| group(*id, name)).collect(),
+            adlists,
+            ..Gravity::default()
+        }
+    }
+
+    fn summary(operations: &[Operation]) -> Vec<String> {
+        operations.iter().map(Operation::to_string).collect()
+    }
+
+    fn added_groups(operations: &[Operation], address: &str) -> Vec<i64> {
+        operations
+            .iter()
+            .find_map(|operation| match operation {
+                Operation::Add(GravityEntry::Adlist(adlist))
+                | Operation::Update(GravityEntry::Adlist(adlist))
+                    if adlist.address == address =>
+                {
+                    Some(adlist.groups.clone())
+                }
+                _ => None,
+            })
+            .expect("adlist is added or updated")
+    }
+
+    #[test]
+    fn mirror_deletes_entries_main_does_not_have() {
+        let main = gravity(
+            &[(0, "Default")],
+            vec![adlist("a", &[0]), adlist("b", &[0])],
+        );
+        let secondary = gravity(
+            &[(0, "Default")],
+            vec![adlist("a", &[0]), adlist("c", &[0])],
+        );
+
+        let operations = plan(
+            &main,
+            &secondary,
+            &InstanceConfig::default(),
+            &Ledger::default(),
+        )
+        .unwrap();
+
+        // Deletions run after all additions
+        assert_eq!(
+            summary(&operations),
+            ["+ adlist block/b", "- adlist block/c"]
+        );
+    }
+
+    #[test]
+    fn merge_only_deletes_entries_the_sync_added() {
+        let main = gravity(&[(0, "Default"), (1, "kids")], vec![adlist("a", &[0])]);
+        let secondary = gravity(
+            &[(0, "Default"), (1, "kids"), (2, "added"), (3, "local")],
+            vec![
+                adlist("a", &[0]),
+                adlist("added", &[0]),
+                adlist("local", &[0]),
+            ],
+        );
+        let instance = InstanceConfig {
+            gravity_strategy: Some(GravityStrategy::Merge),
+            ..InstanceConfig::default()
+        };
+        let mut ledger = Ledger::default();
+        ledger.gravity.insert(
+            "adlist".to_string(),
+            BTreeSet::from(["block/added".to_string()]),
+        );
+        ledger
+            .gravity
+            .insert("group".to_string(), BTreeSet::from(["added".to_string()]));
+
+        let operations = plan(&main, &secondary, &instance, &ledger).unwrap();
+
+        assert_eq!(
+            summary(&operations),
+            ["- adlist block/added", "- group added"]
+        );
+    }
+
+    #[test]
+    fn mirror_deletes_groups_main_does_not_have_except_default() {
+        let main = gravity(&[(0, "Default")], Vec::new());
+        let secondary = gravity(&[(0, "Default"), (4, "old")], Vec::new());
+
+        let operations = plan(
+            &main,
+            &secondary,
+            &InstanceConfig::default(),
+            &Ledger::default(),
+        )
+        .unwrap();
+
+        assert_eq!(summary(&operations), ["- group old"]);
+    }
+
+    #[test]
+    fn group_assignments_are_translated_by_name() {
+        let main = gravity(&[(0, "Default"), (5, "kids")], vec![adlist("a", &[0, 5])]);
+        let secondary = gravity(&[(0, "Default"), (9, "kids")], Vec::new());
+
+        let operations = plan(
+            &main,
+            &secondary,
+            &InstanceConfig::default(),
+            &Ledger::default(),
+        )
+        .unwrap();
+
+        assert_eq!(added_groups(&operations, "a"), [0, 9]);
+    }
+
+    #[test]
+    fn missing_and_colliding_groups_are_errors() {
+        let main = gravity(&[(0, "Default"), (5, "kids")], vec![adlist("a", &[5])]);
+        let instance = InstanceConfig::default();
+        let ledger = Ledger::default();
+
+        let missing = gravity(&[(0, "Default")], Vec::new());
+        assert!(plan(&main, &missing, &instance, &ledger).is_err());
+
+        let colliding = gravity(&[(0, "Default"), (9, "Kids")], Vec::new());
+        assert!(plan(&main, &colliding, &instance, &ledger).is_err());
+        assert!(plan_groups(&main, &colliding, &instance).is_err());
+    }
+
+    #[test]
+    fn plan_groups_adds_missing_and_updates_changed_groups() {
+        let mut main = gravity(&[(0, "Default"), (5, "kids"), (6, "guests")], Vec::new());
+        main.groups[2].enabled = false;
+        let secondary = gravity(&[(0, "Default"), (9, "guests")], Vec::new());
+
+        let operations = plan_groups(&main, &secondary, &InstanceConfig::default()).unwrap();
+
+        assert_eq!(summary(&operations), ["+ group kids", "~ group guests"]);
+    }
+
+    #[test]
+    fn without_group_import_only_missing_groups_are_added() {
+        let main = gravity(
+            &[(0, "Default"), (5, "kids"), (6, "guests")],
+            vec![adlist("a", &[5, 6])],
+        );
+        let mut secondary = gravity(&[(0, "Default"), (9, "guests")], Vec::new());
+        secondary.groups[1].enabled = false;
+        let instance = InstanceConfig {
+            import_options: Some(SyncImportOptions {
+                gravity: GravitySyncIncludes {
+                    group: false,
+                    ..GravitySyncIncludes::default()
+                },
+                ..SyncImportOptions::default()
+            }),
+            ..InstanceConfig::default()
+        };
+
+        let operations = plan_groups(&main, &secondary, &instance).unwrap();
+
+        assert_eq!(summary(&operations), ["+ group kids"]);
+    }
+
+    #[test]
+    fn without_group_import_the_secondarys_assignments_are_kept() {
+        let main = gravity(
+            &[(0, "Default"), (5, "kids")],
+            vec![adlist("a", &[5]), adlist("b", &[5])],
+        );
+        let secondary = gravity(&[(0, "Default"), (3, "guests")], vec![adlist("a", &[0, 3])]);
+        let instance = InstanceConfig {
+            import_options: Some(SyncImportOptions {
+                gravity: GravitySyncIncludes {
+                    group: false,
+                    adlist_by_group: false,
+                    ..GravitySyncIncludes::default()
+                },
+                ..SyncImportOptions::default()
+            }),
+            ..InstanceConfig::default()
+        };
+
+        let operations = plan(&main, &secondary, &instance, &Ledger::default()).unwrap();
+
+        // `a` is unchanged, `b` is new and goes to the default group
+        assert_eq!(summary(&operations), ["+ adlist block/b"]);
+        assert_eq!(added_groups(&operations, "b"), [DEFAULT_GROUP_ID]);
+    }
+
+    #[test]
+    fn group_scope_leaves_other_groups_and_their_entries_alone() {
+        // Main's gravity as `desired_gravity` leaves it for an instance syncing `kids` and `old`
+        let main = gravity(&[(0, "Default"), (1, "kids")], vec![adlist("a", &[1])]);
+        let secondary = gravity(
+            &[(0, "Default"), (1, "kids"), (2, "guests"), (3, "old")],
+            vec![
+                adlist("a", &[1, 2]),
+                adlist("guest-list", &[2]),
+                adlist("kids-list", &[1]),
+            ],
+        );
+        let instance = InstanceConfig {
+            include_groups: Some(vec!["kids".to_string(), "old".to_string()]),
+            ..InstanceConfig::default()
+        };
+
+        let operations = plan(&main, &secondary, &instance, &Ledger::default()).unwrap();
+
+        // `a` keeps its assignment to `guests`, `guest-list` and `guests` are out of scope
+        assert_eq!(
+            summary(&operations),
+            ["- adlist block/kids-list", "- group old"]
+        );
+    }
+
+    #[test]
+    fn group_scope_adds_out_of_scope_assignments_to_updates() {
+        let mut main = gravity(&[(0, "Default"), (1, "kids")], vec![adlist("a", &[1])]);
+        main.adlists[0].comment = Some("changed".to_string());
+        let secondary = gravity(
+            &[(0, "Default"), (1, "kids"), (2, "guests")],
+            vec![adlist("a", &[1, 2])],
+        );
+        let instance = InstanceConfig {
+            include_groups: Some(vec!["kids".to_string()]),
+            ..InstanceConfig::default()
+        };
+
+        let operations = plan(&main, &secondary, &instance, &Ledger::default()).unwrap();
+
+        assert_eq!(summary(&operations), ["~ adlist block/a"]);
+        assert_eq!(added_groups(&operations, "a"), [1, 2]);
+    }
+
+    #[test]
+    fn ownership_follows_applied_operations() {
+        let mut ledger = Ledger::default();
+        let added = GravityEntry::Adlist(adlist("a", &[0]));
+        let gone = GravityEntry::Adlist(adlist("gone", &[0]));
+
+        record_ownership(&mut ledger, &Operation::Add(added.clone()));
+        record_ownership(&mut ledger, &Operation::Add(gone));
+        record_ownership(&mut ledger, &Operation::Update(added.clone()));
+        assert_eq!(
+            ledger.gravity["adlist"],
+            BTreeSet::from(["block/a".to_string(), "block/gone".to_string()])
+        );
+
+        // `gone` was removed from the secondary by other means
+        prune_ownership(
+            &mut ledger,
+            &gravity(&[(0, "Default")], vec![adlist("a", &[0])]),
+        );
+        assert_eq!(
+            ledger.gravity["adlist"],
+            BTreeSet::from(["block/a".to_string()])
+        );
+
+        record_ownership(&mut ledger, &Operation::Delete(added));
+        assert!(ledger.gravity["adlist"].is_empty());
+    }
+}
@@ -1,6 +1,16 @@
+mod archive_store;
 mod cli;
 mod config;
+mod diff;
+mod gravity;
+mod gravity_plan;
 mod pihole_client;
+mod pihole_config;
+mod retry;
+mod schedule;
+mod state;
+mod teleporter;
+mod transform;
 
 use anyhow::Result;
 use cli::Cli;
@@ -8,7 +18,11 @@ use tracing_subscriber::EnvFilter;
 
 fn setup_logging() {
     let env_filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
-    tracing_subscriber::fmt().with_env_filter(env_filter).init();
+    // Logs go to stderr, so command output on stdout can be piped (e.g. `diff --output json`)
+    tracing_subscriber::fmt()
+        .with_env_filter(env_filter)
+        .with_writer(std::io::stderr)
+        .init();
 }
 
 #[tokio::main]
@@ -1,15 +1,18 @@
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use reqwest::{
     multipart::{Form, Part},
-    Client, ClientBuilder, Response, StatusCode,
+    Client, ClientBuilder, Response, StatusCode, Url,
 };
 use serde::Deserialize;
-use serde_json::Value;
-use std::{path::Path, sync::Arc};
+use serde_json::{json, Value};
+use std::{fmt, path::Path, sync::Arc, time::Duration};
 use tokio::sync::Mutex;
 use tracing::{debug, error, info, warn};
 
-use crate::config::InstanceConfig;
+use crate::{
+    config::InstanceConfig,
+    gravity::{self, Adlist, Domain, Gravity, GravityEntry, Group},
+};
 
 #[derive(Debug, Deserialize)]
 struct AuthResponse {
@@ -33,11 +36,61 @@ struct Session {
     sid: Option<String>,
 }
 
+#[derive(Debug, Deserialize)]
+struct GroupsResponse {
+    groups: Vec<Group>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ListsResponse {
+    lists: Vec<Adlist>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DomainsResponse {
+    domains: Vec<Domain>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ClientsResponse {
+    clients: Vec<gravity::Client>,
+}
+
 #[derive(Debug, Deserialize)]
 struct BackupUploadProcessedResponse {
     files: Vec<String>,
 }
 
+/// Errors returned by the Pi-hole API that callers may want to react to
+#[derive(Debug)]
+pub enum ApiError {
+    /// The instance rejected the configured credentials
+    Unauthorized(String),
+    /// The instance responded with a non-success status code
+    Status {
+        status: StatusCode,
+        retry_after: Option<Duration>,
+        body: String,
+    },
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::Unauthorized(host) => write!(
+                f,
+                "Authentication failed for {}. This probably means that the API password is invalid.",
+                host
+            ),
+            ApiError::Status { status, body, .. } => {
+                write!(f, "Request failed with status {}: {}", status, body)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
 #[derive(Debug, Clone)]
 pub struct PiHoleClient {
     base_url: String,
@@ -47,6 +100,7 @@ pub struct PiHoleClient {
 }
 
 const X_FTL_SID_HEADER: &str = "sid";
+const READY_POLL_INTERVAL: Duration = Duration::from_secs(2);
 static APP_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"),);
 
 impl PiHoleClient {
@@ -70,21 +124,20 @@ impl PiHoleClient {
         let auth_url = format!("{}/auth", self.base_url);
         let body = serde_json::json!({ "password": if let Some(pw) = password { pw } else { self.config.api_key.clone() } });
 
-        let response = self
-            .client
-            .post(&auth_url)
-            .json(&body)
-            .send()
-            .await?
-            .json::<AuthResponse>()
-            .await?;
+        let response = self.client.post(&auth_url).json(&body).send().await?;
+
+        if response.status() == StatusCode::UNAUTHORIZED {
+            return Err(ApiError::Unauthorized(self.config.host.clone()).into());
+        }
+
+        let response = check_status(response).await?.json::<AuthResponse>().await?;
 
         debug!("Auth Response: {:?}", response);
 
         if let Some(token) = response.session.sid {
             self.set_token(token.clone()).await?;
         } else {
-            anyhow::bail!("Failed to authenticate: No session ID received. This probably means that the API password is invalid.");
+            return Err(ApiError::Unauthorized(self.config.host.clone()).into());
         }
         Ok(())
     }
@@ -125,7 +178,8 @@ impl PiHoleClient {
             return Ok(false);
         }
 
-        let auth_response = response.json::<AuthResponse>().await?;
+        // Rate limits and server errors have to reach the retry loop as such
+        let auth_response = check_status(response).await?.json::<AuthResponse>().await?;
 
         // Update token if we get a new one
         if let Some(token) = auth_response.session.sid {
@@ -176,44 +230,97 @@ impl PiHoleClient {
 
         let url = format!("{}{}", self.base_url, endpoint);
 
-        self.client
+        let response = self
+            .client
             .post(&url)
             .header(X_FTL_SID_HEADER, self.get_session_token().await?)
             .send()
-            .await?
-            .error_for_status()
+            .await?;
+
+        check_status(response)
+            .await
             .context(format!("POST request failed: {}", url))
     }
 
-    /// Sends an authenticated POST request to the Pi-hole API.
-    async fn delete(&self, endpoint: &str) -> Result<Response> {
+    /// Sends an authenticated PATCH request with a JSON body to the Pi-hole API.
+    async fn patch(&self, endpoint: &str, body: &Value) -> Result<Response> {
         self.ensure_authenticated().await?;
 
         let url = format!("{}{}", self.base_url, endpoint);
 
-        let res = self
+        let response = self
+            .client
+            .patch(&url)
+            .header(X_FTL_SID_HEADER, self.get_session_token().await?)
+            .json(body)
+            .send()
+            .await?;
+
+        check_status(response)
+            .await
+            .context(format!("PATCH request failed: {}", url))
+    }
+
+    /// Sends an authenticated POST request with a JSON body to the Pi-hole API.
+    async fn post_json(&self, endpoint: &str, body: &Value) -> Result<Response> {
+        self.ensure_authenticated().await?;
+
+        let url = format!("{}{}", self.base_url, endpoint);
+
+        let response = self
             .client
             .post(&url)
             .header(X_FTL_SID_HEADER, self.get_session_token().await?)
+            .json(body)
             .send()
             .await?;
 
-        Ok(res)
+        check_status(response)
+            .await
+            .context(format!("POST request failed: {}", url))
     }
 
-    /// Downloads a backup from the Teleporter API.
-    pub async fn download_backup(&self, output_path: &Path) -> Result<()> {
+    /// Sends an authenticated PUT request with a JSON body to the Pi-hole API.
+    async fn put(&self, endpoint: &str, body: &Value) -> Result<Response> {
         self.ensure_authenticated().await?;
 
-        let response = self.get("/teleporter").await?;
-        let bytes = response.bytes().await?;
+        let url = format!("{}{}", self.base_url, endpoint);
 
-        tokio::fs::write(output_path, &bytes)
+        let response = self
+            .client
+            .put(&url)
+            .header(X_FTL_SID_HEADER, self.get_session_token().await?)
+            .json(body)
+            .send()
+            .await?;
+
+        check_status(response)
             .await
-            .context("Failed to write backup file")?;
+            .context(format!("PUT request failed: {}", url))
+    }
 
-        info!("Successfully downloaded backup archive");
-        Ok(())
+    /// Sends an authenticated DELETE request to the Pi-hole API.
+    async fn delete(&self, endpoint: &str) -> Result<Response> {
+        self.ensure_authenticated().await?;
+
+        let url = format!("{}{}", self.base_url, endpoint);
+
+        let res = self
+            .client
+            .delete(&url)
+            .header(X_FTL_SID_HEADER, self.get_session_token().await?)
+            .send()
+            .await?;
+
+        Ok(res)
+    }
+
+    /// Fetches a Teleporter backup archive into memory.
+    pub async fn fetch_backup(&self) -> Result<Vec<u8>> {
+        self.ensure_authenticated().await?;
+
+        let response = check_status(self.get("/teleporter").await?).await?;
+        Ok(response.bytes().await?.to_vec())
     }
 
     /// Uploads a backup to the Teleporter API.
@@ -243,7 +350,7 @@ impl PiHoleClient {
             .send()
             .await?;
 
-        match response.error_for_status() {
+        match check_status(response).await {
             Ok(res) => {
                 info!("Successfully uploaded backup to {}", self.base_url);
                 info!("Processed:");
@@ -255,20 +362,208 @@ impl PiHoleClient {
             }
             Err(err) => {
                 debug!("Error: {}", err.to_string());
-                return Err(err.into());
+                return Err(err);
             }
         }
 
         Ok(())
     }
 
-    /// Triggers a gravity update.
-    pub async fn trigger_gravity_update(&self) -> Result<()> {
-        self.post("/action/gravity").await?;
-        info!("Triggered gravity update on {}", self.base_url);
+    /// Fetches the instance's configuration from the Config API.
+    pub async fn get_config(&self) -> Result<Value> {
+        let response = check_status(self.get("/config").await?).await?;
+        let mut body: Value = response.json().await?;
+
+        body.get_mut("config")
+            .map(Value::take)
+            .context("Config API response did not contain a config object")
+    }
+
+    /// Fetches the instance's configuration including per-key metadata such as restart flags.
+    pub async fn get_config_detailed(&self) -> Result<Value> {
+        let response = check_status(self.get("/config?detailed=true").await?).await?;
+        let mut body: Value = response.json().await?;
+
+        body.get_mut("config")
+            .map(Value::take)
+            .context("Config API response did not contain a config object")
+    }
+
+    /// Applies a partial configuration through the Config API.
+    pub async fn patch_config(&self, config: &Value) -> Result<()> {
+        self.patch("/config", &serde_json::json!({ "config": config }))
+            .await?;
+        info!("Patched configuration on {}", self.base_url);
+        Ok(())
+    }
+
+    /// Reads groups, adlists, domains and clients through the REST API.
+    pub async fn get_gravity(&self) -> Result<Gravity> {
+        let groups: GroupsResponse = check_status(self.get("/groups").await?)
+            .await?
+            .json()
+            .await?;
+        let lists: ListsResponse = check_status(self.get("/lists").await?)
+            .await?
+            .json()
+            .await?;
+        let domains: DomainsResponse = check_status(self.get("/domains").await?)
+            .await?
+            .json()
+            .await?;
+        let clients: ClientsResponse = check_status(self.get("/clients").await?)
+            .await?
+            .json()
+            .await?;
+
+        Ok(Gravity {
+            groups: groups.groups,
+            adlists: lists.lists,
+            domains: domains.domains,
+            clients: clients.clients,
+        })
+    }
+
+    /// Creates a group, adlist, domain or client.
+    pub async fn add_gravity_entry(&self, entry: &GravityEntry) -> Result<()> {
+        let (endpoint, body) = match entry {
+            GravityEntry::Group(group) => (
+                "/groups".to_string(),
+                json!({ "name": group.name, "comment": group.comment, "enabled": group.enabled }),
+            ),
+            GravityEntry::Adlist(adlist) => (
+                format!("/lists?type={}", adlist.list_type.as_str()),
+                json!({
+                    "address": adlist.address,
+                    "comment": adlist.comment,
+                    "groups": adlist.groups,
+                    "enabled": adlist.enabled,
+                }),
+            ),
+            GravityEntry::Domain(domain) => (
+                endpoint(&["domains", domain.domain_type.as_str(), domain.kind.as_str()]),
+                json!({
+                    "domain": domain.domain,
+                    "comment": domain.comment,
+                    "groups": domain.groups,
+                    "enabled": domain.enabled,
+                }),
+            ),
+            GravityEntry::Client(client) => (
+                "/clients".to_string(),
+                json!({ "client": client.client, "comment": client.comment, "groups": client.groups }),
+            ),
+        };
+
+        self.post_json(&endpoint, &body).await?;
+        Ok(())
+    }
+
+    /// Replaces the fields of an existing group, adlist, domain or client.
+    pub async fn update_gravity_entry(&self, entry: &GravityEntry) -> Result<()> {
+        let body = match entry {
+            GravityEntry::Group(group) => {
+                json!({ "name": group.name, "comment": group.comment, "enabled": group.enabled })
+            }
+            GravityEntry::Adlist(adlist) => json!({
+                "type": adlist.list_type.as_str(),
+                "comment": adlist.comment,
+                "groups": adlist.groups,
+                "enabled": adlist.enabled,
+            }),
+            GravityEntry::Domain(domain) => json!({
+                "type": domain.domain_type.as_str(),
+                "kind": domain.kind.as_str(),
+                "comment": domain.comment,
+                "groups": domain.groups,
+                "enabled": domain.enabled,
+            }),
+            GravityEntry::Client(client) => {
+                json!({ "comment": client.comment, "groups": client.groups })
+            }
+        };
+
+        self.put(&entry_endpoint(entry), &body).await?;
+        Ok(())
+    }
+
+    /// Deletes a group, adlist, domain or client.
+    pub async fn delete_gravity_entry(&self, entry: &GravityEntry) -> Result<()> {
+        check_status(self.delete(&entry_endpoint(entry)).await?).await?;
         Ok(())
     }
 
+    /// Runs a gravity update and waits for it to finish, logging its streamed output.
+    /// Fails if the output does not end with gravity's completion message. Failed list
+    /// downloads are only logged, gravity keeps using the cached copies of these lists.
+    pub async fn run_gravity(&self) -> Result<()> {
+        let mut response = self.post("/action/gravity").await?;
+        info!("Started gravity update on {}", self.base_url);
+
+        let mut output = GravityOutput::default();
+        let mut pending = Vec::new();
+        while let Some(chunk) = response
+            .chunk()
+            .await
+            .context("Gravity output ended unexpectedly")?
+        {
+            pending.extend_from_slice(&chunk);
+            while let Some(end) = pending.iter().position(|b| *b == b'\n' || *b == b'\r') {
+                let line = pending.drain(..=end).collect::<Vec<_>>();
+                output.push(&self.config.host, &String::from_utf8_lossy(&line));
+            }
+        }
+        output.push(&self.config.host, &String::from_utf8_lossy(&pending));
+
+        if !output.completed {
+            return Err(anyhow!(
+                "Gravity update on {} did not complete{}",
+                self.config.host,
+                if output.failures.is_empty() {
+                    String::new()
+                } else {
+                    format!(": {}", output.failures.join("; "))
+                }
+            ));
+        }
+
+        info!("Gravity update finished on {}", self.base_url);
+        Ok(())
+    }
+
+    /// Restarts the DNS resolver.
+    pub async fn restart_dns(&self) -> Result<()> {
+        self.post("/action/restartdns").await?;
+        info!("Triggered DNS restart on {}", self.base_url);
+        Ok(())
+    }
+
+    /// Polls the instance until its API answers again, e.g. after a restart.
+    pub async fn wait_until_ready(&self, timeout: Duration) -> Result<()> {
+        let deadline = tokio::time::Instant::now() + timeout;
+
+        loop {
+            let error = match self.get("/dns/blocking").await {
+                Ok(response) => match check_status(response).await {
+                    Ok(_) => return Ok(()),
+                    Err(e) => e,
+                },
+                Err(e) => e,
+            };
+
+            if tokio::time::Instant::now() >= deadline {
+                return Err(error.context(format!(
+                    "{} did not become ready within {} seconds",
+                    self.config.host,
+                    timeout.as_secs()
+                )));
+            }
+
+            debug!("{} is not ready yet: {:#}", self.config.host, error);
+            tokio::time::sleep(READY_POLL_INTERVAL).await;
+        }
+    }
+
     async fn get_session_token(&self) -> Result<String> {
         let session_token = self.session_token.lock().await.clone();
         Ok(session_token.unwrap_or("".to_string()))
@@ -337,7 +632,7 @@ impl PiHoleClient {
 
     pub async fn init_session_keepalive(&self, sync_interval_seconds: u64) -> Result<()> {
         let session_timeout = self.get_session_timeout().await?;
-        let keepalive_interval = sync_interval_seconds - 30;
+        let keepalive_interval = sync_interval_seconds.saturating_sub(30).max(1);
 
         if session_timeout == 0 {
             warn!("Couldn't retrieve session timeout correctly. Not starting keepalive interval.");
@@ -355,3 +650,104 @@ impl PiHoleClient {
         Ok(())
     }
 }
+
+/// Lines `pihole -g` prints, as streamed by `/action/gravity`
+#[derive(Debug, Default)]
+struct GravityOutput {
+    completed: bool,
+    failures: Vec<String>,
+}
+
+impl GravityOutput {
+    fn push(&mut self, host: &str, raw: &str) {
+        let line = strip_ansi(raw);
+        let line = line.trim();
+        if line.is_empty() {
+            return;
+        }
+
+        if line.contains("[✗]") {
+            warn!("{} gravity: {}", host, line);
+            self.failures.push(line.to_string());
+        } else {
+            info!("{} gravity: {}", host, line);
+        }
+
+        if line.starts_with("[✓]") && line.ends_with("Done.") {
+            self.completed = true;
+        }
+    }
+}
+
+/// Removes the terminal color codes gravity adds to its output
+fn strip_ansi(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars();
+
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // Skip the escape sequence up to its final letter
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            result.push(c);
+        }
+    }
+
+    result
+}
+
+/// Turns non-success responses into an [`ApiError::Status`], keeping the `Retry-After` hint.
+async fn check_status(response: Response) -> Result<Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    let retry_after = response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .map(Duration::from_secs);
+    let body = response.text().await.unwrap_or_default();
+
+    Err(ApiError::Status {
+        status,
+        retry_after,
+        body,
+    }
+    .into())
+}
+
+/// Joins path segments into an API endpoint. Segments are percent-encoded, as list
+/// addresses and client identifiers may contain slashes.
+fn endpoint(segments: &[&str]) -> String {
+    let mut url = Url::parse("http://localhost/").expect("static URL is valid");
+    url.path_segments_mut()
+        .expect("static URL can be a base")
+        .extend(segments);
+    url.path().to_string()
+}
+
+/// Endpoint addressing a single existing gravity entry
+fn entry_endpoint(entry: &GravityEntry) -> String {
+    match entry {
+        GravityEntry::Group(group) => endpoint(&["groups", &group.name]),
+        GravityEntry::Adlist(adlist) => format!(
+            "{}?type={}",
+            endpoint(&["lists", &adlist.address]),
+            adlist.list_type.as_str()
+        ),
+        GravityEntry::Domain(domain) => endpoint(&[
+            "domains",
+            domain.domain_type.as_str(),
+            domain.kind.as_str(),
+            &domain.domain,
+        ]),
+        GravityEntry::Client(client) => endpoint(&["clients", &client.client]),
+    }
+}
@@ -0,0 +1,192 @@
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Pi-hole configuration flattened to dotted key paths (e.g. `dns.upstreams`).
+/// Arrays are treated as single values.
+pub type ConfigMap = BTreeMap<String, Value>;
+
+pub fn flatten(config: &Value) -> ConfigMap {
+    let mut entries = ConfigMap::new();
+    flatten_into("", config, &mut entries);
+    entries
+}
+
+fn flatten_into(prefix: &str, value: &Value, entries: &mut ConfigMap) {
+    match value {
+        Value::Object(object) => {
+            for (key, value) in object {
+                let path = if prefix.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", prefix, key)
+                };
+                flatten_into(&path, value, entries);
+            }
+        }
+        leaf => {
+            entries.insert(prefix.to_string(), leaf.clone());
+        }
+    }
+}
+
+/// Builds a nested configuration object from dotted key paths
+pub fn unflatten(entries: &ConfigMap) -> Value {
+    let mut root = Map::new();
+
+    for (path, value) in entries {
+        let mut segments = path.split('.').peekable();
+        let mut current = &mut root;
+
+        while let Some(segment) = segments.next() {
+            if segments.peek().is_none() {
+                current.insert(segment.to_string(), value.clone());
+                break;
+            }
+
+            let child = current
+                .entry(segment.to_string())
+                .or_insert_with(|| Value::Object(Map::new()));
+            if !child.is_object() {
+                *child = Value::Object(Map::new());
+            }
+            current = child.as_object_mut().unwrap();
+        }
+    }
+
+    Value::Object(root)
+}
+
+/// Configuration of an instance as returned by the Config API with `?detailed=true`
+#[derive(Debug, Default)]
+pub struct DetailedConfig {
+    pub values: ConfigMap,
+    /// Paths of keys that only take effect after the DNS resolver is restarted
+    pub restart_required: BTreeSet<String>,
+    /// Paths of keys set through `FTLCONF_*` environment variables, which FTL refuses to change
+    pub env_forced: BTreeSet<String>,
+}
+
+/// Splits a detailed Config API response (`?detailed=true`) into the current values and
+/// the flags of each key.
+pub fn parse_detailed(detailed: &Value) -> DetailedConfig {
+    let mut config = DetailedConfig::default();
+    parse_detailed_into("", detailed, &mut config);
+    config
+}
+
+fn parse_detailed_into(prefix: &str, value: &Value, config: &mut DetailedConfig) {
+    let Value::Object(object) = value else {
+        return;
+    };
+
+    // Every setting is described by an object holding its value and flags
+    if let (Some(setting_value), Some(flags)) = (object.get("value"), object.get("flags")) {
+        config
+            .values
+            .insert(prefix.to_string(), setting_value.clone());
+        let flag = |name: &str| flags.get(name).and_then(Value::as_bool) == Some(true);
+        if flag("restart_dnsmasq") {
+            config.restart_required.insert(prefix.to_string());
+        }
+        if flag("env_var") {
+            config.env_forced.insert(prefix.to_string());
+        }
+        return;
+    }
+
+    for (key, child) in object {
+        let path = if prefix.is_empty() {
+            key.clone()
+        } else {
+            format!("{}.{}", prefix, key)
+        };
+        parse_detailed_into(&path, child, config);
+    }
+}
+
+/// Entries of `main` that are missing or different in `secondary`
+pub fn changed_entries(main: &ConfigMap, secondary: &ConfigMap) -> ConfigMap {
+    main.iter()
+        .filter(|(key, value)| secondary.get(*key) != Some(*value))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect()
+}
+
+/// Matches a dotted config path against a glob pattern. `*` matches any sequence of
+/// characters including dots, `?` matches a single character. A pattern matching a
+/// parent path also matches everything below it, so `webserver` covers `webserver.port`.
+pub fn path_matches(pattern: &str, path: &str) -> bool {
+    glob_match(pattern.as_bytes(), path.as_bytes())
+        || path
+            .match_indices('.')
+            .any(|(i, _)| glob_match(pattern.as_bytes(), &path.as_bytes()[..i]))
+}
+
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` and the text position it currently absorbs up to
+    let mut backtrack = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, absorbed)) => {
+                    p = star + 1;
+                    t = absorbed + 1;
+                    backtrack = Some((star, t));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, text: &str) -> bool {
+        glob_match(pattern.as_bytes(), text.as_bytes())
+    }
+
+    #[test]
+    fn glob_match_literals_and_wildcards() {
+        assert!(matches("dns.upstreams", "dns.upstreams"));
+        assert!(!matches("dns.upstreams", "dns.upstream"));
+        assert!(matches("dns.*", "dns.upstreams"));
+        assert!(matches("dns.*", "dns.rateLimit.count"));
+        assert!(matches("*.port", "webserver.port"));
+        assert!(matches("dns.?osts", "dns.hosts"));
+        assert!(!matches("dns.?osts", "dns.osts"));
+        assert!(matches("*", ""));
+        assert!(!matches("dns.*", "dhcp.active"));
+    }
+
+    #[test]
+    fn glob_match_backtracks_over_stars() {
+        assert!(matches("*a*b", "xaxxab"));
+        assert!(matches("a*b*c", "abbbcbc"));
+        assert!(!matches("a*b*c", "abbbcb"));
+        assert!(matches("**", "anything"));
+    }
+
+    #[test]
+    fn path_matches_covers_children_of_a_matching_parent() {
+        assert!(path_matches("webserver", "webserver.port"));
+        assert!(path_matches("webserver.api", "webserver.api.pwhash"));
+        assert!(path_matches("web*", "webserver.api.pwhash"));
+        assert!(!path_matches("webserver.port", "webserver"));
+        assert!(!path_matches("web", "webserver.port"));
+        assert!(!path_matches("dns.hosts", "dns.hostsFile"));
+    }
+}
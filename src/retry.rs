@@ -0,0 +1,106 @@
+use anyhow::Result;
+use rand::Rng;
+use reqwest::StatusCode;
+use std::{future::Future, time::Duration};
+use tokio::time::sleep;
+use tracing::warn;
+
+use crate::{config::RetryConfig, pihole_client::ApiError};
+
+/// How an error should be handled by the retry loop
+enum Retry {
+    Never,
+    After(Option<Duration>),
+}
+
+/// Runs `operation` until it succeeds, fails with an error that will not go away by
+/// retrying, or the configured number of attempts is used up.
+pub async fn with_retry<T, F, Fut>(
+    policy: &RetryConfig,
+    description: &str,
+    mut operation: F,
+) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let max_attempts = policy.max_attempts.max(1);
+    let mut attempt = 1;
+
+    loop {
+        let err = match operation().await {
+            Ok(value) => return Ok(value),
+            Err(err) => err,
+        };
+
+        let retry_after = match classify(&err) {
+            Retry::After(retry_after) if attempt < max_attempts => retry_after,
+            _ => return Err(err),
+        };
+
+        // A server-supplied hint may not stall the sync beyond the configured maximum
+        let delay = retry_after
+            .map(|retry_after| retry_after.min(Duration::from_secs(policy.max_delay)))
+            .unwrap_or_else(|| backoff_delay(policy, attempt));
+        warn!(
+            "{} failed (attempt {}/{}): {:#}. Retrying in {:.1}s",
+            description,
+            attempt,
+            max_attempts,
+            err,
+            delay.as_secs_f64()
+        );
+
+        sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+fn backoff_delay(policy: &RetryConfig, attempt: u32) -> Duration {
+    let exponential = policy
+        .base_delay
+        .saturating_mul(2u64.saturating_pow(attempt - 1));
+    let delay = Duration::from_secs(exponential.min(policy.max_delay));
+
+    let jitter = policy.jitter.clamp(0.0, 1.0);
+    if jitter == 0.0 {
+        return delay;
+    }
+
+    delay.mul_f64(1.0 + rand::thread_rng().gen_range(0.0..=jitter))
+}
+
+fn classify(err: &anyhow::Error) -> Retry {
+    for cause in err.chain() {
+        if let Some(api_error) = cause.downcast_ref::<ApiError>() {
+            return match api_error {
+                ApiError::Unauthorized(_) => Retry::Never,
+                ApiError::Status {
+                    status,
+                    retry_after,
+                    ..
+                } => match *status {
+                    StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => {
+                        Retry::After(*retry_after)
+                    }
+                    StatusCode::REQUEST_TIMEOUT => Retry::After(None),
+                    status if status.is_server_error() => Retry::After(None),
+                    _ => Retry::Never,
+                },
+            };
+        }
+
+        if let Some(reqwest_error) = cause.downcast_ref::<reqwest::Error>() {
+            if reqwest_error.is_timeout()
+                || reqwest_error.is_connect()
+                || reqwest_error.is_request()
+                || reqwest_error.is_body()
+            {
+                return Retry::After(None);
+            }
+            return Retry::Never;
+        }
+    }
+
+    Retry::Never
+}
@@ -0,0 +1,244 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Local, Utc};
+use rand::Rng;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::{collections::HashMap, fmt, str::FromStr, time::Duration};
+
+/// When a sync runs. Configured either as whole minutes (`120`), a human readable
+/// duration (`"90s"`, `"2h"`) or a cron expression (`"0 */2 * * *"`).
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    raw: RawSchedule,
+    kind: ScheduleKind,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+enum RawSchedule {
+    Minutes(u64),
+    Text(String),
+}
+
+#[derive(Debug, Clone)]
+enum ScheduleKind {
+    /// Runs at every multiple of the duration since the Unix epoch in local time, so `1d` runs
+    /// at local midnight like cron schedules do
+    Every(Duration),
+    /// Runs whenever the cron expression matches in local time
+    Cron(Box<cron::Schedule>),
+}
+
+impl Schedule {
+    fn from_raw(raw: RawSchedule) -> Result<Self> {
+        let kind = match &raw {
+            RawSchedule::Minutes(minutes) => ScheduleKind::Every(Duration::from_secs(minutes * 60)),
+            RawSchedule::Text(text) => parse_text(text)?,
+        };
+
+        match &kind {
+            ScheduleKind::Every(duration) if duration.is_zero() => {
+                return Err(anyhow!("Sync interval must be greater than zero"));
+            }
+            ScheduleKind::Cron(schedule) if schedule.upcoming(Local).next().is_none() => {
+                return Err(anyhow!("Schedule '{}' never runs again", schedule));
+            }
+            _ => {}
+        }
+
+        Ok(Self { raw, kind })
+    }
+
+    /// Next point in time after `now` at which the schedule fires, aligned to local wall-clock
+    /// time. `None` if a cron schedule does not fire anymore (e.g. one limited to past years).
+    pub fn next_after(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match &self.kind {
+            ScheduleKind::Every(interval) => {
+                let offset_ms =
+                    i64::from(now.with_timezone(&Local).offset().local_minus_utc()) * 1000;
+                let interval_ms = interval.as_millis().max(1) as i64;
+                let local_ms = now.timestamp_millis() + offset_ms;
+                let next_ms = (local_ms.div_euclid(interval_ms) + 1) * interval_ms - offset_ms;
+                DateTime::from_timestamp_millis(next_ms)
+            }
+            ScheduleKind::Cron(schedule) => schedule
+                .after(&now.with_timezone(&Local))
+                .next()
+                .map(|next| next.with_timezone(&Utc)),
+        }
+    }
+
+    /// Typical time between two runs. For cron schedules, this is the gap between the next two runs.
+    pub fn approximate_period(&self) -> Duration {
+        match &self.kind {
+            ScheduleKind::Every(interval) => *interval,
+            ScheduleKind::Cron(_) => self
+                .next_after(Utc::now())
+                .and_then(|next| Some(self.next_after(next)? - next))
+                .and_then(|period| period.to_std().ok())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+fn parse_text(text: &str) -> Result<ScheduleKind> {
+    let text = text.trim();
+
+    if let Ok(minutes) = text.parse::<u64>() {
+        return Ok(ScheduleKind::Every(Duration::from_secs(minutes * 60)));
+    }
+
+    if let Ok(duration) = humantime::parse_duration(text) {
+        return Ok(ScheduleKind::Every(duration));
+    }
+
+    // The cron crate expects a seconds field, classic five-field expressions don't have one
+    let expression = if text.split_whitespace().count() == 5 {
+        format!("0 {}", text)
+    } else {
+        text.to_string()
+    };
+
+    cron::Schedule::from_str(&expression)
+        .map(|schedule| ScheduleKind::Cron(Box::new(schedule)))
+        .map_err(|e| {
+            anyhow!(
+                "Invalid schedule '{}': neither a duration nor a cron expression ({})",
+                text,
+                e
+            )
+        })
+}
+
+/// Random delay between zero and `max_jitter`
+fn random_jitter(max_jitter: Duration) -> Duration {
+    if max_jitter.is_zero() {
+        return Duration::ZERO;
+    }
+
+    max_jitter.mul_f64(rand::thread_rng().gen_range(0.0..1.0))
+}
+
+/// Delays scheduled runs by a random jitter drawn once per fire time, so instances whose
+/// schedules fire at the same time stay due together and are synced in one cycle
+#[derive(Debug, Default)]
+pub struct SlotJitter {
+    max_jitter: Duration,
+    drawn: HashMap<DateTime<Utc>, Duration>,
+}
+
+impl SlotJitter {
+    pub fn new(max_jitter: Option<Duration>) -> Self {
+        Self {
+            max_jitter: max_jitter.unwrap_or_default(),
+            drawn: HashMap::new(),
+        }
+    }
+
+    /// Next run of `schedule` after `now`, including the jitter of its fire time
+    pub fn next_run(&mut self, schedule: &Schedule, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let fire_time = schedule.next_after(now)?;
+        self.drawn.retain(|drawn_for, _| *drawn_for > now);
+
+        let max_jitter = self.max_jitter;
+        let jitter = *self
+            .drawn
+            .entry(fire_time)
+            .or_insert_with(|| random_jitter(max_jitter));
+        Some(fire_time + chrono::Duration::from_std(jitter).unwrap_or_default())
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            ScheduleKind::Every(interval) => {
+                write!(f, "every {}", humantime::format_duration(*interval))
+            }
+            ScheduleKind::Cron(schedule) => write!(f, "cron '{}'", schedule),
+        }
+    }
+}
+
+impl Serialize for Schedule {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.raw.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Schedule {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let raw = RawSchedule::deserialize(deserializer)?;
+        Schedule::from_raw(raw).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Serde helpers for optional humantime durations such as `jitter: 30s`
+pub mod optional_duration {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(
+        value: &Option<Duration>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match value {
+            Some(duration) => {
+                serializer.serialize_str(&humantime::format_duration(*duration).to_string())
+            }
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Duration>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|text| humantime::parse_duration(&text).map_err(serde::de::Error::custom))
+            .transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schedule(text: &str) -> Schedule {
+        Schedule::from_raw(RawSchedule::Text(text.to_string())).unwrap()
+    }
+
+    #[test]
+    fn slot_jitter_is_shared_by_schedules_firing_together() {
+        let every = schedule("5m");
+        let same_times = schedule("300s");
+        let mut jitter = SlotJitter::new(Some(Duration::from_secs(3600)));
+        let now = Utc::now();
+
+        let first = jitter.next_run(&every, now).unwrap();
+        let fire_time = every.next_after(now).unwrap();
+        assert_eq!(jitter.next_run(&same_times, now), Some(first));
+        assert!(first >= fire_time);
+        assert!(first <= fire_time + chrono::Duration::hours(1));
+    }
+
+    #[test]
+    fn durations_are_aligned_to_local_time() {
+        let now = Utc::now();
+        let next = schedule("1h").next_after(now).unwrap();
+
+        assert!(next > now);
+        assert!(next - now <= chrono::Duration::hours(1));
+        assert_eq!(
+            next.with_timezone(&Local).format("%M:%S").to_string(),
+            "00:00"
+        );
+    }
+
+    #[test]
+    fn cron_schedules_without_future_runs_are_rejected() {
+        let error = Schedule::from_raw(RawSchedule::Text("0 0 0 1 1 * 2020".to_string()))
+            .unwrap_err()
+            .to_string();
+
+        assert!(error.contains("never runs again"), "{}", error);
+    }
+}
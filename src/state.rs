@@ -0,0 +1,94 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::Path,
+    time::Duration,
+};
+
+const STATE_FILE_NAME: &str = "sync_state.json";
+
+/// Sync state persisted in the cache directory between runs
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SyncState {
+    #[serde(default)]
+    pub instances: BTreeMap<String, InstanceState>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct InstanceState {
+    pub last_attempt: Option<DateTime<Utc>>,
+    pub last_success: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+    #[serde(default)]
+    pub consecutive_failures: u32,
+    /// Content hash of the last archive successfully uploaded to the instance
+    pub applied_hash: Option<String>,
+    /// Duration of the last attempt in milliseconds
+    pub last_duration_ms: Option<u64>,
+    #[serde(default)]
+    pub ledger: Ledger,
+}
+
+/// What the sync has written to an instance on behalf of main. Entries that are not
+/// in the ledger belong to the instance itself and are never removed by a merge.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Ledger {
+    /// Main's entries of merged record arrays (e.g. `dns.hosts`), keyed by config path
+    #[serde(default)]
+    pub dns_records: BTreeMap<String, BTreeSet<String>>,
+    /// Keys of the gravity entries the sync has added, keyed by entry kind (e.g. `adlist`)
+    #[serde(default)]
+    pub gravity: BTreeMap<String, BTreeSet<String>>,
+    /// Fingerprint of the instance's adlists at its last successful gravity update
+    #[serde(default)]
+    pub adlists_hash: Option<String>,
+}
+
+impl InstanceState {
+    pub fn record_success(&mut self, duration: Duration) {
+        let now = Utc::now();
+        self.last_attempt = Some(now);
+        self.last_success = Some(now);
+        self.last_error = None;
+        self.consecutive_failures = 0;
+        self.last_duration_ms = Some(duration.as_millis() as u64);
+    }
+
+    pub fn record_failure(&mut self, error: &anyhow::Error, duration: Duration) {
+        self.last_attempt = Some(Utc::now());
+        self.last_error = Some(format!("{:#}", error));
+        self.consecutive_failures += 1;
+        self.last_duration_ms = Some(duration.as_millis() as u64);
+    }
+}
+
+impl SyncState {
+    pub fn load<P: AsRef<Path>>(cache_location: P) -> Result<Self> {
+        let path = cache_location.as_ref().join(STATE_FILE_NAME);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let content = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read sync state: {:?}", path))?;
+        serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse sync state: {:?}", path))
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, cache_location: P) -> Result<()> {
+        let path = cache_location.as_ref().join(STATE_FILE_NAME);
+        let content = serde_json::to_string_pretty(self)?;
+        fs::write(&path, content).with_context(|| format!("Failed to write sync state: {:?}", path))
+    }
+
+    pub fn instance(&self, host: &str) -> Option<&InstanceState> {
+        self.instances.get(host)
+    }
+
+    pub fn instance_mut(&mut self, host: &str) -> &mut InstanceState {
+        self.instances.entry(host.to_string()).or_default()
+    }
+}
@@ -0,0 +1,428 @@
+use anyhow::{Context, Result};
+use rusqlite::{types::ValueRef, Connection, OpenFlags};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeMap,
+    io::{Cursor, Read, Write},
+    path::Path,
+};
+use tempfile::NamedTempFile;
+use tracing::warn;
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::{
+    config::SyncImportOptions,
+    gravity::{Adlist, Client, Domain, Gravity, Group, ListType},
+};
+
+pub const PIHOLE_TOML: &str = "etc/pihole/pihole.toml";
+pub const DHCP_LEASES: &str = "etc/pihole/dhcp.leases";
+pub const GRAVITY_DB: &str = "etc/pihole/gravity.db";
+
+/// Line prefix FTL writes into pihole.toml on every export
+const TOML_TIMESTAMP_PREFIX: &str = "# Last updated on";
+
+/// In-memory representation of a Pi-hole Teleporter ZIP archive
+#[derive(Debug, Clone)]
+pub struct TeleporterArchive {
+    members: BTreeMap<String, Vec<u8>>,
+}
+
+impl TeleporterArchive {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut zip =
+            ZipArchive::new(Cursor::new(bytes)).context("Failed to read Teleporter archive")?;
+        let mut members = BTreeMap::new();
+
+        for i in 0..zip.len() {
+            let mut file = zip.by_index(i)?;
+            if file.is_dir() {
+                continue;
+            }
+
+            let mut content = Vec::with_capacity(file.size() as usize);
+            file.read_to_end(&mut content)
+                .with_context(|| format!("Failed to extract {} from archive", file.name()))?;
+            members.insert(file.name().to_string(), content);
+        }
+
+        Ok(Self { members })
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let bytes = std::fs::read(path)
+            .with_context(|| format!("Failed to read archive {}", path.display()))?;
+        Self::from_bytes(&bytes)
+    }
+
+    /// Names and sizes of the files contained in the archive
+    pub fn members(&self) -> impl Iterator<Item = (&str, usize)> {
+        self.members
+            .iter()
+            .map(|(name, content)| (name.as_str(), content.len()))
+    }
+
+    /// Parses the archived pihole.toml, if the archive contains one
+    pub fn pihole_toml(&self) -> Result<Option<toml::Table>> {
+        let Some(content) = self.members.get(PIHOLE_TOML) else {
+            return Ok(None);
+        };
+
+        let table = String::from_utf8_lossy(content)
+            .parse::<toml::Table>()
+            .context("Failed to parse pihole.toml from archive")?;
+
+        Ok(Some(table))
+    }
+
+    /// Replaces the archived pihole.toml
+    pub fn set_pihole_toml(&mut self, table: &toml::Table) -> Result<()> {
+        let content = toml::to_string(table).context("Failed to serialize pihole.toml")?;
+        self.members
+            .insert(PIHOLE_TOML.to_string(), content.into_bytes());
+        Ok(())
+    }
+
+    /// Packs the archive into a ZIP file that can be uploaded through the Teleporter API
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+
+        for (name, content) in &self.members {
+            zip.start_file(name.as_str(), options)
+                .with_context(|| format!("Failed to add {} to archive", name))?;
+            zip.write_all(content)?;
+        }
+
+        Ok(zip
+            .finish()
+            .context("Failed to write Teleporter archive")?
+            .into_inner())
+    }
+
+    /// Reads the group, adlist, domain and client tables of the archived gravity database
+    pub fn gravity(&self) -> Result<Option<Gravity>> {
+        let Some(content) = self.members.get(GRAVITY_DB) else {
+            return Ok(None);
+        };
+
+        let (_file, conn) = open_gravity_db(content)?;
+        read_gravity(&conn).map(Some)
+    }
+
+    /// Runs `modify` on a writable copy of the archived gravity database and stores the
+    /// result in the archive. Does nothing if the archive has no gravity database.
+    pub fn modify_gravity(&mut self, modify: impl FnOnce(&Connection) -> Result<()>) -> Result<()> {
+        let Some(content) = self.members.get(GRAVITY_DB) else {
+            return Ok(());
+        };
+
+        let file = write_temp_db(content)?;
+        let conn = Connection::open(file.path())
+            .context("Failed to open gravity database from archive")?;
+        // Rows are rewritten table by table, cascades would drop assignments in between
+        conn.execute_batch("PRAGMA foreign_keys = OFF")?;
+        modify(&conn)?;
+        conn.close().map_err(|(_, e)| e)?;
+
+        let content =
+            std::fs::read(file.path()).context("Failed to read modified gravity database")?;
+        self.members.insert(GRAVITY_DB.to_string(), content);
+        Ok(())
+    }
+
+    /// Fingerprints the parts of the archive a secondary with the given import options
+    /// would actually apply. Volatile data (the export timestamp in pihole.toml, ZIP
+    /// metadata, gravity tables that are not imported) does not affect the hash.
+    pub fn content_hash(&self, options: &SyncImportOptions) -> Result<String> {
+        let mut hasher = Sha256::new();
+
+        // Changing the import options must invalidate previously applied hashes
+        hasher.update(serde_json::to_vec(options)?);
+
+        for (name, content) in &self.members {
+            let normalized = match name.as_str() {
+                PIHOLE_TOML if !options.config => continue,
+                PIHOLE_TOML => normalize_pihole_toml(content),
+                DHCP_LEASES if !options.dhcp_leases => continue,
+                GRAVITY_DB => {
+                    let tables = options.gravity.enabled_tables();
+                    if tables.is_empty() {
+                        continue;
+                    }
+                    hash_gravity_tables(content, &tables)?
+                }
+                _ => content.clone(),
+            };
+
+            hasher.update(name.as_bytes());
+            hasher.update((normalized.len() as u64).to_le_bytes());
+            hasher.update(&normalized);
+        }
+
+        Ok(format!("{:x}", hasher.finalize()))
+    }
+}
+
+fn normalize_pihole_toml(content: &[u8]) -> Vec<u8> {
+    String::from_utf8_lossy(content)
+        .lines()
+        .filter(|line| !line.starts_with(TOML_TIMESTAMP_PREFIX))
+        .collect::<Vec<_>>()
+        .join("\n")
+        .into_bytes()
+}
+
+fn write_temp_db(content: &[u8]) -> Result<NamedTempFile> {
+    let mut file = NamedTempFile::new().context("Failed to create temporary gravity database")?;
+    file.write_all(content)?;
+    file.flush()?;
+    Ok(file)
+}
+
+/// Opens a copy of an archived gravity database. The temporary file has to outlive the connection.
+fn open_gravity_db(content: &[u8]) -> Result<(NamedTempFile, Connection)> {
+    let file = write_temp_db(content)?;
+
+    let conn = Connection::open_with_flags(file.path(), OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .context("Failed to open gravity database from archive")?;
+
+    Ok((file, conn))
+}
+
+/// Columns of a gravity table a sync applies, as read by [`read_gravity`]. Statistics and
+/// timestamps (e.g. `adlist.number`, `date_updated`) change on every gravity run on main.
+fn synced_columns(table: &str) -> &'static str {
+    match table {
+        "group" => "id, enabled, name, description",
+        "adlist" => "id, address, type, enabled, comment",
+        "adlist_by_group" => "adlist_id, group_id",
+        "domainlist" => "id, domain, type, enabled, comment",
+        "domainlist_by_group" => "domainlist_id, group_id",
+        "client" => "id, ip, comment",
+        "client_by_group" => "client_id, group_id",
+        _ => "*",
+    }
+}
+
+/// Hashes the synced columns of the given gravity tables, so the result neither depends on
+/// the SQLite page layout of the exported database file nor on volatile columns.
+fn hash_gravity_tables(content: &[u8], tables: &[&str]) -> Result<Vec<u8>> {
+    let (_file, conn) = open_gravity_db(content)?;
+    let mut hasher = Sha256::new();
+
+    for table in tables {
+        let exists: bool = conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1)",
+            [table],
+            |row| row.get(0),
+        )?;
+        if !exists {
+            continue;
+        }
+
+        hasher.update(table.as_bytes());
+
+        let columns = synced_columns(table);
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM \"{}\" ORDER BY rowid",
+            columns, table
+        ))?;
+        let column_count = stmt.column_count();
+        let mut rows = stmt.query([])?;
+
+        while let Some(row) = rows.next()? {
+            for i in 0..column_count {
+                match row.get_ref(i)? {
+                    ValueRef::Null => hasher.update([0u8]),
+                    ValueRef::Integer(v) => {
+                        hasher.update([1u8]);
+                        hasher.update(v.to_le_bytes());
+                    }
+                    ValueRef::Real(v) => {
+                        hasher.update([2u8]);
+                        hasher.update(v.to_le_bytes());
+                    }
+                    ValueRef::Text(v) | ValueRef::Blob(v) => {
+                        hasher.update([3u8]);
+                        hasher.update((v.len() as u64).to_le_bytes());
+                        hasher.update(v);
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(hasher.finalize().to_vec())
+}
+
+/// Rows of a group assignment table as (entry ID, group ID) pairs
+pub fn read_group_assignments(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+) -> Result<Vec<(i64, i64)>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {}, group_id FROM \"{}\" ORDER BY group_id",
+        column, table
+    ))?;
+    let assignments = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(assignments)
+}
+
+fn groups_of(assignments: &[(i64, i64)], id: i64) -> Vec<i64> {
+    assignments
+        .iter()
+        .filter(|(entry_id, _)| *entry_id == id)
+        .map(|(_, group_id)| *group_id)
+        .collect()
+}
+
+fn read_gravity(conn: &Connection) -> Result<Gravity> {
+    let groups = conn
+        .prepare("SELECT id, name, enabled, description FROM \"group\" ORDER BY id")?
+        .query_map([], |row| {
+            Ok(Group {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                enabled: row.get(2)?,
+                comment: row.get(3)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    let adlist_groups = read_group_assignments(conn, "adlist_by_group", "adlist_id")?;
+    let adlists = conn
+        .prepare("SELECT id, address, type, enabled, comment FROM adlist ORDER BY id")?
+        .query_map([], |row| {
+            let id = row.get(0)?;
+            let list_type: i64 = row.get(2)?;
+            Ok(Adlist {
+                id,
+                address: row.get(1)?,
+                list_type: if list_type == 1 {
+                    ListType::Allow
+                } else {
+                    ListType::Block
+                },
+                enabled: row.get(3)?,
+                comment: row.get(4)?,
+                groups: groups_of(&adlist_groups, id),
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    let domain_groups = read_group_assignments(conn, "domainlist_by_group", "domainlist_id")?;
+    let mut domains = Vec::new();
+    let mut stmt =
+        conn.prepare("SELECT id, domain, type, enabled, comment FROM domainlist ORDER BY id")?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let id = row.get(0)?;
+        let db_type: i64 = row.get(2)?;
+        let Some((domain_type, kind)) = Domain::type_and_kind_from_db(db_type) else {
+            warn!("Skipping domain with unknown type {} in archive", db_type);
+            continue;
+        };
+
+        domains.push(Domain {
+            id,
+            domain: row.get(1)?,
+            domain_type,
+            kind,
+            enabled: row.get(3)?,
+            comment: row.get(4)?,
+            groups: groups_of(&domain_groups, id),
+        });
+    }
+
+    let client_groups = read_group_assignments(conn, "client_by_group", "client_id")?;
+    let clients = conn
+        .prepare("SELECT id, ip, comment FROM client ORDER BY id")?
+        .query_map([], |row| {
+            let id = row.get(0)?;
+            Ok(Client {
+                id,
+                client: row.get(1)?,
+                comment: row.get(2)?,
+                groups: groups_of(&client_groups, id),
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    Ok(Gravity {
+        groups,
+        adlists,
+        domains,
+        clients,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Archive with a gravity database holding one adlist with the given statistics
+    fn archive_with_adlist(number: i64, date_updated: i64) -> TeleporterArchive {
+        let file = NamedTempFile::new().unwrap();
+        let conn = Connection::open(file.path()).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE \"group\" (id INTEGER PRIMARY KEY, enabled BOOLEAN, name TEXT, \
+                 date_added INTEGER, date_modified INTEGER, description TEXT);
+             CREATE TABLE adlist (id INTEGER PRIMARY KEY, address TEXT, enabled BOOLEAN, \
+                 date_added INTEGER, date_modified INTEGER, comment TEXT, date_updated INTEGER, \
+                 number INTEGER, invalid_domains INTEGER, status INTEGER, abp_entries INTEGER, \
+                 type INTEGER);
+             CREATE TABLE adlist_by_group (adlist_id INTEGER, group_id INTEGER, \
+                 PRIMARY KEY (adlist_id, group_id));
+             INSERT INTO \"group\" VALUES (0, 1, 'Default', 1, 1, NULL);
+             INSERT INTO adlist_by_group VALUES (1, 0);",
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO adlist VALUES (1, 'https://example.com/list.txt', 1, 1, ?1, NULL, \
+                 ?1, ?2, 0, 1, 0, 0)",
+            (date_updated, number),
+        )
+        .unwrap();
+        conn.close().unwrap();
+
+        TeleporterArchive {
+            members: BTreeMap::from([(
+                GRAVITY_DB.to_string(),
+                std::fs::read(file.path()).unwrap(),
+            )]),
+        }
+    }
+
+    #[test]
+    fn content_hash_ignores_adlist_statistics() {
+        let options = SyncImportOptions::default();
+        let before = archive_with_adlist(1000, 1_700_000_000)
+            .content_hash(&options)
+            .unwrap();
+        let after = archive_with_adlist(1250, 1_700_086_400)
+            .content_hash(&options)
+            .unwrap();
+
+        assert_eq!(before, after);
+    }
+
+    #[test]
+    fn content_hash_covers_synced_columns() {
+        let options = SyncImportOptions::default();
+        let mut archive = archive_with_adlist(1000, 1_700_000_000);
+        let before = archive.content_hash(&options).unwrap();
+
+        archive
+            .modify_gravity(|conn| {
+                conn.execute("UPDATE adlist SET enabled = 0", [])?;
+                Ok(())
+            })
+            .unwrap();
+
+        assert_ne!(before, archive.content_hash(&options).unwrap());
+    }
+}
@@ -0,0 +1,855 @@
+use anyhow::{anyhow, Context, Result};
+use ipnet::IpNet;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::{collections::BTreeSet, net::IpAddr};
+
+use crate::{
+    config::{ClientRewrite, ConflictRule, InstanceConfig, RecordStrategy},
+    gravity::{Gravity, Group, GroupMap, DEFAULT_GROUP_ID},
+    pihole_config::{flatten, path_matches, unflatten, ConfigMap},
+    state::Ledger,
+    teleporter::{read_group_assignments, TeleporterArchive},
+};
+
+/// Record arrays that can be merged instead of replaced
+const MERGEABLE_RECORDS: [&str; 2] = ["dns.hosts", "dns.cnameRecords"];
+
+/// Gravity tables with group assignments, with their assignment table and its entry column
+const GROUPED_TABLES: [(&str, &str, &str); 3] = [
+    ("adlist", "adlist_by_group", "adlist_id"),
+    ("domainlist", "domainlist_by_group", "domainlist_id"),
+    ("client", "client_by_group", "client_id"),
+];
+
+/// Keys identifying an instance (credentials, certificates, network interface).
+/// Syncing them could lock us out of a secondary or break its networking.
+const BUILTIN_PROTECTED_KEYS: [&str; 5] = [
+    "webserver.api.pwhash",
+    "webserver.api.app_pwhash",
+    "webserver.api.totp_secret",
+    "webserver.tls.cert",
+    "dns.interface",
+];
+
+/// Whether the value of `path` is taken from main, as opposed to keeping the secondary's own
+fn is_synced(instance: &InstanceConfig, path: &str) -> bool {
+    let filtered_out = instance
+        .config_filter
+        .as_ref()
+        .is_some_and(|filter| !filter.allows(path));
+
+    !filtered_out && !is_protected(instance, path)
+}
+
+fn is_protected(instance: &InstanceConfig, path: &str) -> bool {
+    let protected_keys = instance.protected_keys.clone().unwrap_or_default();
+
+    let protected = BUILTIN_PROTECTED_KEYS
+        .iter()
+        .copied()
+        .chain(protected_keys.add.iter().map(String::as_str))
+        .any(|pattern| path_matches(pattern, path));
+
+    protected
+        && !protected_keys
+            .allow
+            .iter()
+            .any(|pattern| path_matches(pattern, path))
+}
+
+/// Configuration a secondary should end up with: main's value for every synced key,
+/// the secondary's own value for filtered out and protected keys, merged DNS records
+/// and the instance's overrides on top. `ledger` is updated to the main-originated
+/// records of the result.
+pub fn desired_config(
+    main: &ConfigMap,
+    secondary: &ConfigMap,
+    instance: &InstanceConfig,
+    ledger: &mut Ledger,
+) -> Result<ConfigMap> {
+    let mut desired = ConfigMap::new();
+    for (path, value) in main {
+        if is_synced(instance, path) {
+            desired.insert(path.clone(), value.clone());
+        } else if let Some(own_value) = secondary.get(path) {
+            desired.insert(path.clone(), own_value.clone());
+        }
+    }
+    for (path, value) in secondary {
+        if !main.contains_key(path) && !is_synced(instance, path) {
+            desired.insert(path.clone(), value.clone());
+        }
+    }
+
+    if let Some(records) = instance
+        .dns_records
+        .as_ref()
+        .filter(|records| records.strategy == RecordStrategy::Merge)
+    {
+        for path in MERGEABLE_RECORDS {
+            let (Some(main_records), true) = (main.get(path), is_synced(instance, path)) else {
+                continue;
+            };
+
+            let owned = ledger.dns_records.entry(path.to_string()).or_default();
+            let merged = merge_records(
+                path,
+                &string_entries(main_records),
+                &string_entries(secondary.get(path).unwrap_or(&Value::Null)),
+                owned,
+                records.conflict,
+            )
+            .with_context(|| format!("Failed to merge {} for {}", path, instance.host))?;
+            desired.insert(path.to_string(), Value::from(merged));
+        }
+    }
+
+    desired.extend(resolve_overrides(instance)?);
+    Ok(desired)
+}
+
+fn string_entries(value: &Value) -> Vec<String> {
+    value
+        .as_array()
+        .map(|entries| {
+            entries
+                .iter()
+                .filter_map(|entry| entry.as_str().map(str::to_string))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Combines main's records with the secondary's own ones. Entries of the secondary that
+/// are not in `owned` belong to the secondary; `owned` is replaced by main's kept entries.
+fn merge_records(
+    path: &str,
+    main: &[String],
+    secondary: &[String],
+    owned: &mut BTreeSet<String>,
+    conflict: ConflictRule,
+) -> Result<Vec<String>> {
+    let mut own = secondary
+        .iter()
+        .filter(|entry| !owned.contains(*entry))
+        .cloned()
+        .collect::<Vec<_>>();
+    let mut from_main = main
+        .iter()
+        .filter(|entry| !own.contains(entry))
+        .cloned()
+        .collect::<Vec<_>>();
+
+    let conflicting = |a: &String, b: &String| {
+        let names = record_names(path, a);
+        record_names(path, b)
+            .iter()
+            .any(|name| names.contains(name))
+    };
+
+    match conflict {
+        ConflictRule::MainWins => {
+            own.retain(|own_entry| !from_main.iter().any(|entry| conflicting(entry, own_entry)))
+        }
+        ConflictRule::SecondaryWins => {
+            from_main.retain(|entry| !own.iter().any(|own_entry| conflicting(entry, own_entry)))
+        }
+        ConflictRule::Fail => {
+            for entry in &from_main {
+                if let Some(own_entry) = own.iter().find(|own_entry| conflicting(entry, own_entry))
+                {
+                    return Err(anyhow!(
+                        "'{}' from main conflicts with '{}' on the secondary",
+                        entry,
+                        own_entry
+                    ));
+                }
+            }
+        }
+    }
+
+    *owned = from_main.iter().cloned().collect();
+    from_main.extend(own);
+    Ok(from_main)
+}
+
+/// Names a record defines: the hostnames of a `dns.hosts` entry (`IP host [host...]`),
+/// the aliases of a `dns.cnameRecords` entry (`cname[,cname...],target[,ttl]`).
+fn record_names(path: &str, entry: &str) -> Vec<String> {
+    let names: Vec<&str> = if path == "dns.cnameRecords" {
+        let mut fields = entry.split(',').map(str::trim).collect::<Vec<_>>();
+        if fields.len() > 2 && fields.last().is_some_and(|ttl| ttl.parse::<u32>().is_ok()) {
+            fields.pop();
+        }
+        fields.pop();
+        fields
+    } else {
+        entry.split_whitespace().skip(1).collect()
+    };
+
+    names.into_iter().map(str::to_lowercase).collect()
+}
+
+/// The instance's overrides with variables substituted. Nested values are flattened,
+/// so `dns: {domain: x}` and `dns.domain: x` are equivalent.
+fn resolve_overrides(instance: &InstanceConfig) -> Result<ConfigMap> {
+    let Some(overrides) = &instance.overrides else {
+        return Ok(ConfigMap::new());
+    };
+
+    let resolved = overrides
+        .iter()
+        .map(|(path, value)| {
+            substitute_value(value, instance)
+                .map(|value| (path.clone(), value))
+                .with_context(|| format!("Invalid override {} of {}", path, instance.host))
+        })
+        .collect::<Result<ConfigMap>>()?;
+
+    Ok(flatten(&unflatten(&resolved)))
+}
+
+fn substitute_value(value: &Value, instance: &InstanceConfig) -> Result<Value> {
+    Ok(match value {
+        Value::String(text) => Value::String(substitute(text, instance)?),
+        Value::Array(items) => Value::Array(
+            items
+                .iter()
+                .map(|item| substitute_value(item, instance))
+                .collect::<Result<_>>()?,
+        ),
+        Value::Object(object) => Value::Object(
+            object
+                .iter()
+                .map(|(key, item)| Ok((key.clone(), substitute_value(item, instance)?)))
+                .collect::<Result<_>>()?,
+        ),
+        other => other.clone(),
+    })
+}
+
+/// Replaces `{{name}}` placeholders with the instance's variables. `host` is always defined.
+fn substitute(text: &str, instance: &InstanceConfig) -> Result<String> {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find("{{") {
+        let end = rest[start..]
+            .find("}}")
+            .map(|offset| start + offset)
+            .ok_or_else(|| anyhow!("Unterminated variable in '{}'", text))?;
+        let name = rest[start + 2..end].trim();
+
+        let value = instance
+            .vars
+            .as_ref()
+            .and_then(|vars| vars.get(name))
+            .cloned()
+            .or_else(|| (name == "host").then(|| instance.host.clone()))
+            .ok_or_else(|| anyhow!("Unknown variable '{}'", name))?;
+
+        result.push_str(&rest[..start]);
+        result.push_str(&value);
+        rest = &rest[end + 2..];
+    }
+
+    result.push_str(rest);
+    Ok(result)
+}
+
+/// Whether the archive uploaded to this instance differs from main's archive. This is
+/// always the case if the config is imported, protected keys are never taken from main,
+/// if group assignments are imported without the groups themselves, if entries are
+/// filtered by tag or group and if clients are rewritten.
+pub fn rewrites_archive(instance: &InstanceConfig) -> bool {
+    imports_config(instance)
+        || remaps_groups(instance)
+        || instance.tag_filter.is_some()
+        || instance.scopes_groups()
+        || instance.client_rewrites.is_some()
+}
+
+fn imports_config(instance: &InstanceConfig) -> bool {
+    instance
+        .import_options
+        .as_ref()
+        .is_none_or(|options| options.config)
+}
+
+/// Whether group assignments are imported without the group table. They then reference
+/// main's group IDs and have to be translated to the secondary's groups of the same name.
+fn remaps_groups(instance: &InstanceConfig) -> bool {
+    let gravity = instance.import_options.clone().unwrap_or_default().gravity;
+    !gravity.group
+        && (gravity.adlist_by_group || gravity.domainlist_by_group || gravity.client_by_group)
+}
+
+/// Builds the archive to upload to a secondary from main's archive and the secondary's
+/// current one. Returns `None` if main's archive can be uploaded as it is.
+pub fn rewrite_archive(
+    main: &TeleporterArchive,
+    secondary: &TeleporterArchive,
+    instance: &InstanceConfig,
+    ledger: &mut Ledger,
+) -> Result<Option<TeleporterArchive>> {
+    let mut archive = main.clone();
+    let mut rewritten = false;
+
+    if let (true, Some(main_toml)) = (imports_config(instance), main.pihole_toml()?) {
+        let secondary_toml = secondary.pihole_toml()?.unwrap_or_default();
+        let desired = desired_config(
+            &flatten(&serde_json::to_value(main_toml)?),
+            &flatten(&serde_json::to_value(secondary_toml)?),
+            instance,
+            ledger,
+        )?;
+        let table: toml::Table = serde_json::from_value(unflatten(&desired))
+            .with_context(|| format!("Failed to build pihole.toml for {}", instance.host))?;
+
+        archive.set_pihole_toml(&table)?;
+        rewritten = true;
+    }
+
+    if let Some(filter) = &instance.tag_filter {
+        archive
+            .modify_gravity(|conn| {
+                for (table, assignments, column) in GROUPED_TABLES {
+                    let rejected = conn
+                        .prepare(&format!("SELECT id, comment FROM \"{}\"", table))?
+                        .query_map([], |row| {
+                            Ok((row.get::<_, i64>(0)?, row.get::<_, Option<String>>(1)?))
+                        })?
+                        .collect::<rusqlite::Result<Vec<_>>>()?
+                        .into_iter()
+                        .filter(|(_, comment)| !filter.allows(comment.as_deref()))
+                        .map(|(id, _)| id);
+
+                    for id in rejected {
+                        conn.execute(&format!("DELETE FROM \"{}\" WHERE id = ?1", table), [id])?;
+                        conn.execute(
+                            &format!("DELETE FROM \"{}\" WHERE {} = ?1", assignments, column),
+                            [id],
+                        )?;
+                    }
+                }
+                Ok(())
+            })
+            .with_context(|| format!("Failed to filter gravity by tag for {}", instance.host))?;
+        rewritten = true;
+    }
+
+    if instance.scopes_groups() {
+        scope_groups(&mut archive, instance).with_context(|| {
+            format!("Failed to limit gravity to the groups of {}", instance.host)
+        })?;
+        rewritten = true;
+    }
+
+    if let Some(rules) = &instance.client_rewrites {
+        archive
+            .modify_gravity(|conn| {
+                let clients = conn
+                    .prepare("SELECT id, ip FROM client")?
+                    .query_map([], |row| {
+                        Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+                    })?
+                    .collect::<rusqlite::Result<Vec<_>>>()?;
+
+                for (id, client) in clients {
+                    let rewritten = rewrite_client(&client, rules)?;
+                    if rewritten != client {
+                        conn.execute("UPDATE client SET ip = ?1 WHERE id = ?2", (&rewritten, id))
+                            .with_context(|| {
+                                format!("Cannot rewrite {} to {}", client, rewritten)
+                            })?;
+                    }
+                }
+                Ok(())
+            })
+            .with_context(|| format!("Failed to rewrite clients for {}", instance.host))?;
+        rewritten = true;
+    }
+
+    if remaps_groups(instance) {
+        remap_groups(&mut archive, secondary, instance)
+            .with_context(|| format!("Failed to map groups of {} by name", instance.host))?;
+        rewritten = true;
+    }
+
+    Ok(rewritten.then_some(archive))
+}
+
+/// Main's gravity as the instance should get it: without the adlists, domains and clients
+/// its tag filter rejects, without the groups outside its group scope and with its client
+/// rewrites applied. Entries are only kept with their assignments to synced groups, and
+/// dropped if none remain.
+pub fn desired_gravity(gravity: &Gravity, instance: &InstanceConfig) -> Result<Gravity> {
+    let mut filtered = gravity.clone();
+
+    if let Some(filter) = &instance.tag_filter {
+        filtered
+            .adlists
+            .retain(|adlist| filter.allows(adlist.comment.as_deref()));
+        filtered
+            .domains
+            .retain(|domain| filter.allows(domain.comment.as_deref()));
+        filtered
+            .clients
+            .retain(|client| filter.allows(client.comment.as_deref()));
+    }
+
+    if instance.scopes_groups() {
+        filtered
+            .groups
+            .retain(|group| instance.syncs_group(&group.name));
+        let synced = filtered
+            .groups
+            .iter()
+            .map(|group| group.id)
+            .collect::<BTreeSet<_>>();
+
+        filtered
+            .adlists
+            .iter_mut()
+            .for_each(|adlist| adlist.groups.retain(|id| synced.contains(id)));
+        filtered.adlists.retain(|adlist| !adlist.groups.is_empty());
+        filtered
+            .domains
+            .iter_mut()
+            .for_each(|domain| domain.groups.retain(|id| synced.contains(id)));
+        filtered.domains.retain(|domain| !domain.groups.is_empty());
+        filtered
+            .clients
+            .iter_mut()
+            .for_each(|client| client.groups.retain(|id| synced.contains(id)));
+        filtered.clients.retain(|client| !client.groups.is_empty());
+    }
+
+    if let Some(rules) = &instance.client_rewrites {
+        for client in &mut filtered.clients {
+            client.client = rewrite_client(&client.client, rules)
+                .with_context(|| format!("Invalid client rewrite of {}", instance.host))?;
+        }
+    }
+
+    Ok(filtered)
+}
+
+/// Applies the first matching rewrite rule to a client identifier
+fn rewrite_client(client: &str, rules: &[ClientRewrite]) -> Result<String> {
+    for rule in rules {
+        if let Some(rewritten) = apply_rewrite(rule, client)? {
+            return Ok(rewritten);
+        }
+    }
+    Ok(client.to_string())
+}
+
+/// The rewritten client identifier, or `None` if the rule does not match it
+fn apply_rewrite(rule: &ClientRewrite, client: &str) -> Result<Option<String>> {
+    let Ok(from) = rule.from.parse::<IpNet>() else {
+        return Ok(rule
+            .from
+            .eq_ignore_ascii_case(client)
+            .then(|| rule.to.clone()));
+    };
+    let to = rule
+        .to
+        .parse::<IpNet>()
+        .with_context(|| format!("'{}' is not a network", rule.to))?;
+    let same_family = matches!(
+        (from, to),
+        (IpNet::V4(_), IpNet::V4(_)) | (IpNet::V6(_), IpNet::V6(_))
+    );
+    if !same_family || from.prefix_len() != to.prefix_len() {
+        return Err(anyhow!(
+            "{} and {} are not networks of the same size",
+            rule.from,
+            rule.to
+        ));
+    }
+
+    // Clients are single addresses or subnets
+    let (address, prefix_len) = match client.parse::<IpNet>() {
+        Ok(network) => (network.addr(), Some(network.prefix_len())),
+        Err(_) => match client.parse::<IpAddr>() {
+            Ok(address) => (address, None),
+            Err(_) => return Ok(None),
+        },
+    };
+    if !from.contains(&address) || prefix_len.is_some_and(|len| len < from.prefix_len()) {
+        return Ok(None);
+    }
+
+    let mapped = match (address, to.network(), from.hostmask()) {
+        (IpAddr::V4(address), IpAddr::V4(network), IpAddr::V4(hostmask)) => {
+            IpAddr::V4((u32::from(network) | (u32::from(address) & u32::from(hostmask))).into())
+        }
+        (IpAddr::V6(address), IpAddr::V6(network), IpAddr::V6(hostmask)) => {
+            IpAddr::V6((u128::from(network) | (u128::from(address) & u128::from(hostmask))).into())
+        }
+        _ => return Ok(None),
+    };
+
+    Ok(Some(match prefix_len {
+        Some(len) => format!("{}/{}", mapped, len),
+        None => mapped.to_string(),
+    }))
+}
+
+/// Removes the groups outside the instance's group scope from the archive, together with
+/// their assignments and the entries left without any group. The default group stays, as
+/// every Pi-hole needs it.
+pub fn scope_groups(archive: &mut TeleporterArchive, instance: &InstanceConfig) -> Result<()> {
+    let Some(gravity) = archive.gravity()? else {
+        return Ok(());
+    };
+    let excluded = gravity
+        .groups
+        .iter()
+        .filter(|group| !instance.syncs_group(&group.name))
+        .map(|group| group.id)
+        .collect::<Vec<_>>();
+
+    archive.modify_gravity(|conn| {
+        for id in excluded {
+            for (_, assignments, _) in GROUPED_TABLES {
+                conn.execute(
+                    &format!("DELETE FROM \"{}\" WHERE group_id = ?1", assignments),
+                    [id],
+                )?;
+            }
+            if id != DEFAULT_GROUP_ID {
+                conn.execute("DELETE FROM \"group\" WHERE id = ?1", [id])?;
+            }
+        }
+
+        for (table, assignments, column) in GROUPED_TABLES {
+            conn.execute(
+                &format!(
+                    "DELETE FROM \"{}\" WHERE id NOT IN (SELECT {} FROM \"{}\")",
+                    table, column, assignments
+                ),
+                [],
+            )?;
+        }
+        Ok(())
+    })
+}
+
+/// Main's groups the secondary lacks if group assignments are mapped by name. They have to be
+/// created on the secondary before the archive is built. Groups whose name only differs in
+/// case or surrounding whitespace are not missing but collisions, which the mapping rejects.
+pub fn missing_groups(
+    main: &TeleporterArchive,
+    secondary: &TeleporterArchive,
+    instance: &InstanceConfig,
+) -> Result<Vec<Group>> {
+    let (true, Some(main_gravity), Some(secondary_gravity)) = (
+        remaps_groups(instance),
+        main.gravity()?,
+        secondary.gravity()?,
+    ) else {
+        return Ok(Vec::new());
+    };
+
+    Ok(main_gravity
+        .groups
+        .into_iter()
+        .filter(|group| {
+            instance.syncs_group(&group.name)
+                && !secondary_gravity
+                    .groups
+                    .iter()
+                    .any(|own| own.name.trim().eq_ignore_ascii_case(group.name.trim()))
+        })
+        .collect())
+}
+
+/// Adds groups to the archive under new IDs, as creating them on the instance would
+pub fn add_groups(archive: &mut TeleporterArchive, groups: &[Group]) -> Result<()> {
+    archive.modify_gravity(|conn| {
+        for group in groups {
+            conn.execute(
+                "INSERT INTO \"group\" (enabled, name, description) VALUES (?1, ?2, ?3)",
+                (group.enabled, &group.name, &group.comment),
+            )?;
+        }
+        Ok(())
+    })
+}
+
+/// Translates the imported group assignments of the archive to the secondary's group IDs.
+/// The group table itself is replaced by the secondary's, which keeps the archive consistent
+/// for comparisons; it is not imported anyway.
+fn remap_groups(
+    archive: &mut TeleporterArchive,
+    secondary: &TeleporterArchive,
+    instance: &InstanceConfig,
+) -> Result<()> {
+    let (Some(main_gravity), Some(secondary_gravity)) = (archive.gravity()?, secondary.gravity()?)
+    else {
+        return Ok(());
+    };
+    let groups = GroupMap::by_name(&main_gravity.groups, &secondary_gravity.groups);
+    let includes = instance.import_options.clone().unwrap_or_default().gravity;
+
+    archive.modify_gravity(|conn| {
+        for (table, column, imported) in [
+            ("adlist_by_group", "adlist_id", includes.adlist_by_group),
+            ("domainlist_by_group", "domainlist_id", includes.domainlist_by_group),
+            ("client_by_group", "client_id", includes.client_by_group),
+        ] {
+            if !imported {
+                continue;
+            }
+
+            let assignments = read_group_assignments(conn, table, column)?
+                .into_iter()
+                .map(|(entry_id, group_id)| {
+                    let group_id = groups.translate(&[group_id])?[0];
+                    Ok((entry_id, group_id))
+                })
+                .collect::<Result<Vec<_>>>()
+                .with_context(|| format!("Cannot translate {}", table))?;
+
+            conn.execute(&format!("DELETE FROM \"{}\"", table), [])?;
+            for (entry_id, group_id) in assignments {
+                conn.execute(
+                    &format!(
+                        "INSERT INTO \"{}\" ({}, group_id) VALUES (?1, ?2)",
+                        table, column
+                    ),
+                    (entry_id, group_id),
+                )?;
+            }
+        }
+
+        conn.execute("DELETE FROM \"group\"", [])?;
+        for group in &secondary_gravity.groups {
+            conn.execute(
+                "INSERT OR REPLACE INTO \"group\" (id, enabled, name, description) VALUES (?1, ?2, ?3, ?4)",
+                (group.id, group.enabled, &group.name, &group.comment),
+            )?;
+        }
+        Ok(())
+    })
+}
+
+/// Change detection hash of main's archive for one secondary. Includes the instance's
+/// rewrite settings, so editing them triggers a new upload.
+pub fn archive_hash(archive: &TeleporterArchive, instance: &InstanceConfig) -> Result<String> {
+    let content_hash =
+        archive.content_hash(&instance.import_options.clone().unwrap_or_default())?;
+    if !rewrites_archive(instance) {
+        return Ok(content_hash);
+    }
+
+    let mut hasher = Sha256::new();
+    hasher.update(content_hash.as_bytes());
+    hasher.update(serde_json::to_vec(&(
+        &instance.config_filter,
+        &instance.overrides,
+        &instance.vars,
+        &instance.dns_records,
+        &instance.protected_keys,
+        &instance.tag_filter,
+        &instance.include_groups,
+        &instance.exclude_groups,
+        &instance.client_rewrites,
+    ))?);
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(entries: &[&str]) -> Vec<String> {
+        entries.iter().map(|entry| entry.to_string()).collect()
+    }
+
+    fn set(entries: &[&str]) -> BTreeSet<String> {
+        entries.iter().map(|entry| entry.to_string()).collect()
+    }
+
+    #[test]
+    fn merge_records_keeps_own_records_and_tracks_main_ones() {
+        let mut owned = BTreeSet::new();
+        let merged = merge_records(
+            "dns.hosts",
+            &strings(&["192.168.1.10 nas.lan"]),
+            &strings(&["10.2.0.5 printer.branch"]),
+            &mut owned,
+            ConflictRule::MainWins,
+        )
+        .unwrap();
+
+        assert_eq!(
+            merged,
+            strings(&["192.168.1.10 nas.lan", "10.2.0.5 printer.branch"])
+        );
+        assert_eq!(owned, set(&["192.168.1.10 nas.lan"]));
+    }
+
+    #[test]
+    fn merge_records_removes_records_main_deleted() {
+        let mut owned = set(&["192.168.1.10 nas.lan", "192.168.1.11 old.lan"]);
+        let merged = merge_records(
+            "dns.hosts",
+            &strings(&["192.168.1.10 nas.lan"]),
+            &strings(&[
+                "192.168.1.10 nas.lan",
+                "192.168.1.11 old.lan",
+                "10.2.0.5 printer.branch",
+            ]),
+            &mut owned,
+            ConflictRule::MainWins,
+        )
+        .unwrap();
+
+        assert_eq!(
+            merged,
+            strings(&["192.168.1.10 nas.lan", "10.2.0.5 printer.branch"])
+        );
+        assert_eq!(owned, set(&["192.168.1.10 nas.lan"]));
+    }
+
+    #[test]
+    fn merge_records_resolves_conflicts_by_rule() {
+        let main = strings(&["192.168.1.10 NAS.lan"]);
+        let secondary = strings(&["10.2.0.10 nas.lan"]);
+
+        let mut owned = BTreeSet::new();
+        let merged = merge_records(
+            "dns.hosts",
+            &main,
+            &secondary,
+            &mut owned,
+            ConflictRule::MainWins,
+        )
+        .unwrap();
+        assert_eq!(merged, main);
+
+        let mut owned = BTreeSet::new();
+        let merged = merge_records(
+            "dns.hosts",
+            &main,
+            &secondary,
+            &mut owned,
+            ConflictRule::SecondaryWins,
+        )
+        .unwrap();
+        assert_eq!(merged, secondary);
+        assert!(owned.is_empty());
+
+        let mut owned = BTreeSet::new();
+        assert!(merge_records(
+            "dns.hosts",
+            &main,
+            &secondary,
+            &mut owned,
+            ConflictRule::Fail
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn merge_records_compares_cname_aliases_without_target_and_ttl() {
+        let mut owned = BTreeSet::new();
+        let merged = merge_records(
+            "dns.cnameRecords",
+            &strings(&["www.lan,nas.lan,300"]),
+            &strings(&["www.lan,printer.lan", "nas.lan,printer.lan"]),
+            &mut owned,
+            ConflictRule::MainWins,
+        )
+        .unwrap();
+
+        assert_eq!(
+            merged,
+            strings(&["www.lan,nas.lan,300", "nas.lan,printer.lan"])
+        );
+    }
+
+    fn rule(from: &str, to: &str) -> ClientRewrite {
+        ClientRewrite {
+            from: from.to_string(),
+            to: to.to_string(),
+        }
+    }
+
+    #[test]
+    fn apply_rewrite_moves_addresses_into_the_target_network() {
+        let rule = rule("10.1.0.0/16", "10.2.0.0/16");
+
+        assert_eq!(
+            apply_rewrite(&rule, "10.1.5.20").unwrap().as_deref(),
+            Some("10.2.5.20")
+        );
+        assert_eq!(apply_rewrite(&rule, "10.3.5.20").unwrap(), None);
+        assert_eq!(apply_rewrite(&rule, "laptop.lan").unwrap(), None);
+        assert_eq!(apply_rewrite(&rule, "fd00::1").unwrap(), None);
+    }
+
+    #[test]
+    fn apply_rewrite_keeps_the_prefix_of_subnets() {
+        let rule = rule("10.1.0.0/16", "10.2.0.0/16");
+
+        // Narrower than `from`: moved along with its prefix length
+        assert_eq!(
+            apply_rewrite(&rule, "10.1.7.0/24").unwrap().as_deref(),
+            Some("10.2.7.0/24")
+        );
+        assert_eq!(
+            apply_rewrite(&rule, "10.1.0.0/16").unwrap().as_deref(),
+            Some("10.2.0.0/16")
+        );
+        // Wider than `from`: only partly inside, left alone
+        assert_eq!(apply_rewrite(&rule, "10.0.0.0/8").unwrap(), None);
+    }
+
+    #[test]
+    fn apply_rewrite_maps_ipv6_networks() {
+        let rule = rule("fd00:1::/64", "fd00:2::/64");
+
+        assert_eq!(
+            apply_rewrite(&rule, "fd00:1::abcd").unwrap().as_deref(),
+            Some("fd00:2::abcd")
+        );
+        assert_eq!(apply_rewrite(&rule, "10.1.5.20").unwrap(), None);
+    }
+
+    #[test]
+    fn apply_rewrite_rejects_networks_of_different_size_or_family() {
+        assert!(apply_rewrite(&rule("10.1.0.0/16", "10.2.0.0/24"), "10.1.5.20").is_err());
+        assert!(apply_rewrite(&rule("10.1.0.0/16", "fd00::/16"), "10.1.5.20").is_err());
+        assert!(apply_rewrite(&rule("10.1.0.0/16", "printer.lan"), "10.1.5.20").is_err());
+    }
+
+    #[test]
+    fn apply_rewrite_replaces_other_identifiers_exactly() {
+        let rule = rule("12:34:56:78:9A:BC", "12:34:56:78:9a:bd");
+
+        assert_eq!(
+            apply_rewrite(&rule, "12:34:56:78:9a:bc")
+                .unwrap()
+                .as_deref(),
+            Some("12:34:56:78:9a:bd")
+        );
+        assert_eq!(apply_rewrite(&rule, "12:34:56:78:9a:bcd").unwrap(), None);
+    }
+
+    #[test]
+    fn rewrite_client_applies_the_first_matching_rule() {
+        let rules = [
+            rule("10.1.5.0/24", "10.9.5.0/24"),
+            rule("10.1.0.0/16", "10.2.0.0/16"),
+        ];
+
+        assert_eq!(rewrite_client("10.1.5.20", &rules).unwrap(), "10.9.5.20");
+        assert_eq!(rewrite_client("10.1.6.20", &rules).unwrap(), "10.2.6.20");
+        assert_eq!(rewrite_client("laptop.lan", &rules).unwrap(), "laptop.lan");
+    }
+}